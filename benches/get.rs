@@ -0,0 +1,38 @@
+//! Benchmarks `get` on stores of growing size. The store file is only read again when another
+//! handle changed it, so the latency of `get` should stay flat as the store grows.
+
+use std::env;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use microkv::{helpers, MicroKV};
+
+static TEST_PASSWORD: &str = "TEST_PASSWORD";
+
+/// Creates a store of `entries` values of 1 KiB each.
+fn store(entries: usize) -> MicroKV {
+    let mut dir = env::temp_dir();
+    dir.push("microkv-bench");
+    let name = format!("bench_get_{}", entries);
+    let _ = std::fs::remove_file(helpers::get_db_path_with_base_path(&name, dir.clone()));
+    let kv = MicroKV::new_with_base_path(name, dir).with_pwd_clear(TEST_PASSWORD);
+    for i in 0..entries {
+        kv.put(format!("key{}", i), &"-".repeat(1024)).unwrap();
+    }
+    kv.commit().unwrap();
+    kv
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for entries in [10, 100, 1000].iter() {
+        let kv = store(*entries);
+        group.bench_with_input(BenchmarkId::from_parameter(entries), entries, |b, _| {
+            b.iter(|| kv.get_as_unwrap::<String>("key0").unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_get);
+criterion_main!(benches);
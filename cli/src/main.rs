@@ -2,12 +2,20 @@
 //! server instance or be used as a client that interacts with a local persistent store or
 //! one on another host and volume.
 
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
 
-use microkv::errors::Result;
-use microkv::MicroKV;
+use microkv::dropbox;
+use microkv::errors::{ErrorType, KVError, Result};
+use microkv::keyring::Credential;
+use microkv::provider::{EnvProvider, KeyProvider, KeyfileProvider, PromptProvider};
+use microkv::{helpers, MicroKV};
 
 use clap::{App, Arg, ArgMatches, SubCommand};
+use secstr::{SecStr, SecVec};
 
 fn parse_args<'a>() -> ArgMatches<'a> {
     // define key arg to avoid repetition
@@ -48,6 +56,43 @@ fn parse_args<'a>() -> ArgMatches<'a> {
                 .help("Interact with the database without encryption.")
                 .takes_value(false),
         )
+        // read the password from an environment variable instead of prompting
+        .arg(
+            Arg::with_name("password-env")
+                .long("password-env")
+                .required(false)
+                .conflicts_with_all(&["unsafe", "keyfile"])
+                .help("Read the password from the given environment variable.")
+                .takes_value(true),
+        )
+        // unlock with a keyfile instead of a password
+        .arg(
+            Arg::with_name("keyfile")
+                .long("keyfile")
+                .required(false)
+                .conflicts_with("unsafe")
+                .help("Unlock the database with the contents of the given file.")
+                .takes_value(true),
+        )
+        // seal values to the public key of a drop box, without being able to read them back
+        .arg(
+            Arg::with_name("public-key")
+                .long("public-key")
+                .required(false)
+                .conflicts_with("secret-key")
+                .help("Put values into a drop box with the given hex-encoded public key.")
+                .takes_value(true),
+        )
+        // read values of a drop box
+        .arg(
+            Arg::with_name("secret-key")
+                .long("secret-key")
+                .required(false)
+                .help(
+                    "Read values of a drop box with the hex-encoded secret key in the given file.",
+                )
+                .takes_value(true),
+        )
         // `put` adds a new key and value entry.
         .subcommand(
             SubCommand::with_name("put")
@@ -94,9 +139,59 @@ fn parse_args<'a>() -> ArgMatches<'a> {
                         .help("Include values when printing"),
                 ),
         )
+        // `passwd` changes the password of the database
+        .subcommand(
+            SubCommand::with_name("passwd")
+                .about("Changes the password the database is encrypted with"),
+        )
+        // `dropbox` turns a new database into a drop box
+        .subcommand(
+            SubCommand::with_name("dropbox")
+                .about("Turns a new database into a drop box, and prints its public key")
+                .arg(
+                    Arg::with_name("secret-key-out")
+                        .long("secret-key-out")
+                        .required(true)
+                        .takes_value(true)
+                        .help("New file to write the hex-encoded secret key to, owner-only"),
+                ),
+        )
         .get_matches()
 }
 
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(KVError {
+                    error: ErrorType::Custom,
+                    msg: Some("keys must be hex-encoded".to_string()),
+                })
+        })
+        .collect()
+}
+
+/// Writes the hex-encoded `secret_key` to a new file at `path`, only readable by its owner.
+fn write_secret_key(path: &str, secret_key: &SecStr) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    let hex = SecVec::new(to_hex(secret_key.unsecure()).into_bytes());
+    file.write_all(hex.unsecure())?;
+    file.sync_all()?;
+    Ok(())
+}
+
 fn run() -> Result<()> {
     let args: ArgMatches = parse_args();
 
@@ -105,20 +200,72 @@ fn run() -> Result<()> {
 
     // check if database file exists
     let database: &str = args.value_of("DATABASE").unwrap();
-    let dbpath: PathBuf = MicroKV::get_db_path(database);
+    let dbpath: PathBuf = helpers::get_db_path(database);
 
-    // initialize key-value object through database name
-    let mut kv: MicroKV = match dbpath.as_path().exists() {
-        true => MicroKV::open(database)?,
-        false => MicroKV::new(database),
+    // TODO: consume structured inputs either as string format or file
+
+    // choose where the key is read from, unless --unsafe set. Drop boxes need no password
+    // unless one is given explicitly.
+    let drop_box: bool = args.is_present("public-key")
+        || args.is_present("secret-key")
+        || args.subcommand_name() == Some("dropbox");
+    let provider: Option<Box<dyn KeyProvider>> = if args.is_present("unsafe") {
+        None
+    } else if let Some(var) = args.value_of("password-env") {
+        Some(Box::new(EnvProvider::new(var)))
+    } else if let Some(path) = args.value_of("keyfile") {
+        Some(Box::new(KeyfileProvider::new(path)))
+    } else if drop_box {
+        None
+    } else {
+        Some(Box::new(PromptProvider::default()))
     };
+    let credential: Option<Credential> = provider.map(|p| p.credential()).transpose()?;
 
-    // TODO: consume structured inputs either as string format or file
+    // initialize key-value object through database name. The password is given on open, as
+    // encrypted stores written before 0.3.0 are only migrated with it.
+    let pwd: Option<&str> = match credential {
+        Some(Credential::Password(ref pwd)) => std::str::from_utf8(pwd.unsecure()).ok(),
+        _ => None,
+    };
+    let exists: bool = dbpath.as_path().exists();
+    let mut kv: MicroKV = match (exists, pwd) {
+        (true, Some(pwd)) => MicroKV::open_with_pwd(database, pwd)?,
+        (true, None) => MicroKV::open(database)?,
+        (false, _) => MicroKV::new(database),
+    };
+    if let Some(ref key) = credential {
+        if !(exists && pwd.is_some()) {
+            kv = kv.try_with_credential(key.clone())?;
+        }
+    }
 
-    // safely parse password unless --unsafe set
-    if !args.is_present("unsafe") {
-        let pass = rpassword::read_password_from_tty(Some("Password: ")).unwrap();
-        kv = kv.with_pwd_clear(pass);
+    // a new database becomes a drop box for the public key given
+    if let Some(hex) = args.value_of("public-key") {
+        let mut public_key = [0; dropbox::KEY_BYTES];
+        let bytes = from_hex(hex)?;
+        if bytes.len() != public_key.len() {
+            return Err(KVError {
+                error: ErrorType::Custom,
+                msg: Some("public key must be 32 bytes long".to_string()),
+            });
+        }
+        public_key.copy_from_slice(&bytes);
+        kv = kv.with_public_key(public_key);
+        if kv.public_key()? != Some(public_key) {
+            return Err(KVError {
+                error: ErrorType::Custom,
+                msg: Some(format!(
+                    "database `{}` is not a drop box for this public key",
+                    database
+                )),
+            });
+        }
+    }
+    if let Some(path) = args.value_of("secret-key") {
+        let hex = SecVec::new(fs::read(path)?);
+        let hex = String::from_utf8_lossy(hex.unsecure());
+        kv = kv.try_with_secret_key(SecVec::new(from_hex(hex.trim())?))?;
     }
 
     // otherwise, interact with local db normally
@@ -134,8 +281,8 @@ fn run() -> Result<()> {
         ("get", Some(subargs)) => {
             let key: &str = subargs.value_of("key").unwrap();
 
-            let value: Option<String> = kv.get(key)?;
-            println!("{}", value.unwrap_or("<None>".to_string()));
+            let value: Option<String> = kv.get_as(key)?;
+            println!("{}", value.unwrap_or_else(|| "<None>".to_string()));
         }
         ("rm", Some(subargs)) => {
             let key: &str = subargs.value_of("key").unwrap();
@@ -154,6 +301,41 @@ fn run() -> Result<()> {
                 println!("{}", key);
             }
         }
+        ("passwd", Some(_)) => {
+            let old = credential.ok_or(KVError {
+                error: ErrorType::Custom,
+                msg: Some("cannot change the password of an unencrypted database".to_string()),
+            })?;
+            let new = rpassword::read_password_from_tty(Some("New password: ")).unwrap();
+            let confirm =
+                rpassword::read_password_from_tty(Some("Confirm new password: ")).unwrap();
+            if new != confirm {
+                return Err(KVError {
+                    error: ErrorType::Custom,
+                    msg: Some("passwords do not match".to_string()),
+                });
+            }
+
+            kv.change_credential(&old, &Credential::password(new))?;
+            println!("Changed password of database `{}`", database);
+        }
+        ("dropbox", Some(subargs)) => {
+            let (public_key, secret_key) = dropbox::gen_keypair();
+            kv = kv.with_public_key(public_key);
+            if kv.public_key()? != Some(public_key) {
+                return Err(KVError {
+                    error: ErrorType::Custom,
+                    msg: Some(format!("database `{}` already holds values", database)),
+                });
+            }
+
+            // the secret key is never printed, so that it stays out of logs and shell history
+            let path: &str = subargs.value_of("secret-key-out").unwrap();
+            write_secret_key(path, &secret_key)?;
+            kv.commit()?;
+            println!("Public key: {}", to_hex(&public_key));
+            println!("Secret key written to `{}`", path);
+        }
         _ => {}
     }
 
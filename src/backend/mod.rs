@@ -0,0 +1,38 @@
+//! Defines the cryptographic primitives microkv is built on. They are provided either by
+//! libsodium through `sodiumoxide`, with the default `sodium` feature, or by the pure-Rust
+//! RustCrypto crates with the `rustcrypto` feature, which avoids linking libsodium in static
+//! and cross-compiled builds. If both are enabled, RustCrypto is used.
+//!
+//! Both backends implement the same constructions, so that a store written with one can be
+//! read with the other.
+
+#[cfg(not(any(feature = "sodium", feature = "rustcrypto")))]
+compile_error!("either the `sodium` or the `rustcrypto` feature of microkv must be enabled");
+
+#[cfg(feature = "rustcrypto")]
+mod rustcrypto;
+#[cfg(feature = "rustcrypto")]
+pub(crate) use self::rustcrypto::*;
+
+#[cfg(all(feature = "sodium", not(feature = "rustcrypto")))]
+mod sodium;
+#[cfg(all(feature = "sodium", not(feature = "rustcrypto")))]
+pub(crate) use self::sodium::*;
+
+/// Size of every symmetric key: data keys, derived keys and names keys.
+pub(crate) const KEY_BYTES: usize = 32;
+
+/// Nonce sizes of XSalsa20-Poly1305, XChaCha20-Poly1305 and AES-256-GCM.
+pub(crate) const XSALSA20POLY1305_NONCE_BYTES: usize = 24;
+pub(crate) const XCHACHA20POLY1305_NONCE_BYTES: usize = 24;
+pub(crate) const AES256GCM_NONCE_BYTES: usize = 12;
+
+/// Salt size and interactive cost parameters of Argon2id, as defined by libsodium.
+pub(crate) const ARGON2ID_SALT_BYTES: usize = 16;
+pub(crate) const ARGON2ID_OPSLIMIT_INTERACTIVE: u64 = 2;
+pub(crate) const ARGON2ID_MEMLIMIT_INTERACTIVE: u64 = 67_108_864;
+
+/// Generates a random symmetric key.
+pub(crate) fn gen_key() -> Vec<u8> {
+    randombytes(KEY_BYTES)
+}
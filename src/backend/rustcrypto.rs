@@ -0,0 +1,183 @@
+//! Pure-Rust backend, through the RustCrypto crates.
+
+use aes_gcm::Aes256Gcm;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use crypto_box::aead::OsRng;
+use crypto_box::{PublicKey, SecretKey};
+use crypto_secretbox::XSalsa20Poly1305;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::{AES256GCM_NONCE_BYTES, XCHACHA20POLY1305_NONCE_BYTES, XSALSA20POLY1305_NONCE_BYTES};
+
+/// Generates `size` random bytes. Like libsodium, aborts if the OS can't provide them.
+pub(crate) fn randombytes(size: usize) -> Vec<u8> {
+    let mut bytes = vec![0; size];
+    getrandom::getrandom(&mut bytes).expect("cannot read from the OS random number generator");
+    bytes
+}
+
+pub(crate) fn sha256(msg: &[u8]) -> [u8; 32] {
+    Sha256::digest(msg).into()
+}
+
+fn hmac(key: &[u8], msg: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes keys of any size
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    mac.update(msg);
+    mac
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    hmac(key, msg).finalize().into_bytes().into()
+}
+
+/// Checks `tag` against the HMAC-SHA-256 of `msg` in constant time.
+pub(crate) fn verify_hmac_sha256(tag: &[u8], key: &[u8], msg: &[u8]) -> bool {
+    hmac(key, msg).verify_slice(tag).is_ok()
+}
+
+/// Fills `out` with the Argon2id hash of `pwd`, with `memlimit` in bytes as in libsodium.
+pub(crate) fn argon2id(
+    out: &mut [u8],
+    pwd: &[u8],
+    salt: &[u8],
+    opslimit: u64,
+    memlimit: u64,
+) -> Result<(), ()> {
+    let params = Params::new(
+        (memlimit / 1024) as u32,
+        opslimit as u32,
+        1,
+        Some(out.len()),
+    )
+    .map_err(|_| ())?;
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(pwd, salt, out)
+        .map_err(|_| ())
+}
+
+/// Seals `msg` with any of the AEADs, once the size of the nonce is checked.
+fn seal<A: Aead + KeyInit>(
+    msg: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    nonce_bytes: usize,
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    if nonce.len() != nonce_bytes {
+        return Err(());
+    }
+    let aead = A::new_from_slice(key).map_err(|_| ())?;
+    aead.encrypt(nonce.into(), Payload { msg, aad: ad })
+        .map_err(|_| ())
+}
+
+fn open<A: Aead + KeyInit>(
+    ciphertext: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    nonce_bytes: usize,
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    if nonce.len() != nonce_bytes {
+        return Err(());
+    }
+    let aead = A::new_from_slice(key).map_err(|_| ())?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: ad,
+    };
+    aead.decrypt(nonce.into(), payload).map_err(|_| ())
+}
+
+/// `secretbox`, with the tag in front of the ciphertext.
+pub(crate) fn xsalsa20poly1305_seal(msg: &[u8], nonce: &[u8], key: &[u8]) -> Result<Vec<u8>, ()> {
+    seal::<XSalsa20Poly1305>(msg, &[], nonce, XSALSA20POLY1305_NONCE_BYTES, key)
+}
+
+pub(crate) fn xsalsa20poly1305_open(
+    ciphertext: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    open::<XSalsa20Poly1305>(ciphertext, &[], nonce, XSALSA20POLY1305_NONCE_BYTES, key)
+}
+
+pub(crate) fn xchacha20poly1305_seal(
+    msg: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    seal::<XChaCha20Poly1305>(msg, ad, nonce, XCHACHA20POLY1305_NONCE_BYTES, key)
+}
+
+pub(crate) fn xchacha20poly1305_open(
+    ciphertext: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    open::<XChaCha20Poly1305>(ciphertext, ad, nonce, XCHACHA20POLY1305_NONCE_BYTES, key)
+}
+
+/// Whether AES-256-GCM can be used. The RustCrypto implementation falls back to software on
+/// CPUs without AES-NI.
+pub(crate) fn aes256gcm_is_available() -> bool {
+    true
+}
+
+pub(crate) fn aes256gcm_seal(
+    msg: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    seal::<Aes256Gcm>(msg, ad, nonce, AES256GCM_NONCE_BYTES, key)
+}
+
+pub(crate) fn aes256gcm_open(
+    ciphertext: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    open::<Aes256Gcm>(ciphertext, ad, nonce, AES256GCM_NONCE_BYTES, key)
+}
+
+/// Generates an X25519 key pair, as its public and secret keys.
+pub(crate) fn x25519_keypair() -> ([u8; 32], Vec<u8>) {
+    let secret_key = SecretKey::generate(&mut OsRng);
+    (
+        secret_key.public_key().to_bytes(),
+        secret_key.to_bytes().to_vec(),
+    )
+}
+
+/// Returns the public key matching an X25519 secret key.
+pub(crate) fn x25519_public_key(secret_key: &[u8]) -> Result<[u8; 32], ()> {
+    let secret_key = SecretKey::from_slice(secret_key).map_err(|_| ())?;
+    Ok(secret_key.public_key().to_bytes())
+}
+
+/// `crypto_box_seal`, sealing `msg` to `public_key` under a fresh ephemeral key pair.
+pub(crate) fn sealedbox_seal(msg: &[u8], public_key: &[u8]) -> Result<Vec<u8>, ()> {
+    let public_key = PublicKey::from_slice(public_key).map_err(|_| ())?;
+    public_key.seal(&mut OsRng, msg).map_err(|_| ())
+}
+
+/// The public key is derived from the secret key, as libsodium checks it against the one given.
+pub(crate) fn sealedbox_open(
+    ciphertext: &[u8],
+    public_key: &[u8],
+    secret_key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let secret_key = SecretKey::from_slice(secret_key).map_err(|_| ())?;
+    if secret_key.public_key().as_bytes()[..] != *public_key {
+        return Err(());
+    }
+    secret_key.unseal(ciphertext).map_err(|_| ())
+}
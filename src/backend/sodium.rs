@@ -0,0 +1,150 @@
+//! libsodium backend, through `sodiumoxide`.
+
+use sodiumoxide::crypto::aead::{aes256gcm, xchacha20poly1305_ietf};
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::box_;
+use sodiumoxide::crypto::hash;
+use sodiumoxide::crypto::pwhash::argon2id13::{self, MemLimit, OpsLimit, Salt};
+use sodiumoxide::crypto::{sealedbox, secretbox};
+use sodiumoxide::utils;
+
+/// Generates `size` random bytes.
+pub(crate) fn randombytes(size: usize) -> Vec<u8> {
+    sodiumoxide::randombytes::randombytes(size)
+}
+
+pub(crate) fn sha256(msg: &[u8]) -> [u8; 32] {
+    hash::sha256::hash(msg).0
+}
+
+pub(crate) fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    let mut state = hmacsha256::State::init(key);
+    state.update(msg);
+    state.finalize().0
+}
+
+/// Checks `tag` against the HMAC-SHA-256 of `msg` in constant time.
+pub(crate) fn verify_hmac_sha256(tag: &[u8], key: &[u8], msg: &[u8]) -> bool {
+    utils::memcmp(tag, &hmac_sha256(key, msg))
+}
+
+/// Fills `out` with the Argon2id hash of `pwd`, with `memlimit` in bytes.
+pub(crate) fn argon2id(
+    out: &mut [u8],
+    pwd: &[u8],
+    salt: &[u8],
+    opslimit: u64,
+    memlimit: u64,
+) -> Result<(), ()> {
+    let salt = Salt::from_slice(salt).ok_or(())?;
+    argon2id13::derive_key(
+        out,
+        pwd,
+        &salt,
+        OpsLimit(opslimit as usize),
+        MemLimit(memlimit as usize),
+    )
+    .map(|_| ())
+}
+
+/// `secretbox`, with the tag in front of the ciphertext.
+pub(crate) fn xsalsa20poly1305_seal(msg: &[u8], nonce: &[u8], key: &[u8]) -> Result<Vec<u8>, ()> {
+    let key = secretbox::Key::from_slice(key).ok_or(())?;
+    let nonce = secretbox::Nonce::from_slice(nonce).ok_or(())?;
+    Ok(secretbox::seal(msg, &nonce, &key))
+}
+
+pub(crate) fn xsalsa20poly1305_open(
+    ciphertext: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let key = secretbox::Key::from_slice(key).ok_or(())?;
+    let nonce = secretbox::Nonce::from_slice(nonce).ok_or(())?;
+    secretbox::open(ciphertext, &nonce, &key)
+}
+
+pub(crate) fn xchacha20poly1305_seal(
+    msg: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let key = xchacha20poly1305_ietf::Key::from_slice(key).ok_or(())?;
+    let nonce = xchacha20poly1305_ietf::Nonce::from_slice(nonce).ok_or(())?;
+    Ok(xchacha20poly1305_ietf::seal(msg, Some(ad), &nonce, &key))
+}
+
+pub(crate) fn xchacha20poly1305_open(
+    ciphertext: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let key = xchacha20poly1305_ietf::Key::from_slice(key).ok_or(())?;
+    let nonce = xchacha20poly1305_ietf::Nonce::from_slice(nonce).ok_or(())?;
+    xchacha20poly1305_ietf::open(ciphertext, Some(ad), &nonce, &key)
+}
+
+/// libsodium's AES-256-GCM, which it only detects AES-NI for once `sodium_init` has run.
+fn aes256gcm() -> Result<aes256gcm::Aes256Gcm, ()> {
+    sodiumoxide::init()?;
+    aes256gcm::Aes256Gcm::new()
+}
+
+/// Whether AES-256-GCM can be used. libsodium only provides it on CPUs with AES-NI.
+pub(crate) fn aes256gcm_is_available() -> bool {
+    aes256gcm().is_ok()
+}
+
+pub(crate) fn aes256gcm_seal(
+    msg: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let aes = aes256gcm()?;
+    let key = aes256gcm::Key::from_slice(key).ok_or(())?;
+    let nonce = aes256gcm::Nonce::from_slice(nonce).ok_or(())?;
+    Ok(aes.seal(msg, Some(ad), &nonce, &key))
+}
+
+pub(crate) fn aes256gcm_open(
+    ciphertext: &[u8],
+    ad: &[u8],
+    nonce: &[u8],
+    key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let aes = aes256gcm()?;
+    let key = aes256gcm::Key::from_slice(key).ok_or(())?;
+    let nonce = aes256gcm::Nonce::from_slice(nonce).ok_or(())?;
+    aes.open(ciphertext, Some(ad), &nonce, &key)
+}
+
+/// Generates an X25519 key pair, as its public and secret keys.
+pub(crate) fn x25519_keypair() -> ([u8; 32], Vec<u8>) {
+    let (public_key, secret_key) = box_::gen_keypair();
+    (public_key.0, secret_key.0.to_vec())
+}
+
+/// Returns the public key matching an X25519 secret key.
+pub(crate) fn x25519_public_key(secret_key: &[u8]) -> Result<[u8; 32], ()> {
+    let secret_key = box_::SecretKey::from_slice(secret_key).ok_or(())?;
+    Ok(secret_key.public_key().0)
+}
+
+/// `crypto_box_seal`, sealing `msg` to `public_key` under a fresh ephemeral key pair.
+pub(crate) fn sealedbox_seal(msg: &[u8], public_key: &[u8]) -> Result<Vec<u8>, ()> {
+    let public_key = box_::PublicKey::from_slice(public_key).ok_or(())?;
+    Ok(sealedbox::seal(msg, &public_key))
+}
+
+pub(crate) fn sealedbox_open(
+    ciphertext: &[u8],
+    public_key: &[u8],
+    secret_key: &[u8],
+) -> Result<Vec<u8>, ()> {
+    let public_key = box_::PublicKey::from_slice(public_key).ok_or(())?;
+    let secret_key = box_::SecretKey::from_slice(secret_key).ok_or(())?;
+    sealedbox::open(ciphertext, &public_key, &secret_key)
+}
@@ -0,0 +1,126 @@
+//! Defines the authenticated ciphers values can be sealed with. The cipher is recorded in the
+//! store header, chosen with `MicroKV::with_cipher` when the store is created, and every value
+//! and key slot of the store is sealed and opened with it.
+//!
+//! Values are bound to the namespace and key name they are stored under through the associated
+//! data of the AEAD, so that a ciphertext moved onto another entry of the file fails to open.
+
+use secstr::SecStr;
+use serde::{Deserialize, Serialize};
+
+use crate::backend;
+use crate::errors::{ErrorType, KVError, Result};
+
+/// Authenticated cipher recorded in the store header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Cipher {
+    /// XSalsa20-Poly1305 `secretbox`, which takes no associated data. Only used by stores
+    /// created before 0.4.0, until their password is changed.
+    XSalsa20Poly1305,
+
+    /// XChaCha20-Poly1305, with the namespace and key name of each value as associated data.
+    #[default]
+    XChaCha20Poly1305,
+
+    /// AES-256-GCM, with the namespace and key name of each value as associated data. Needs a
+    /// CPU with AES-NI, and a random 96-bit nonce per value.
+    Aes256Gcm,
+}
+
+impl Cipher {
+    /// Whether this cipher leaves values unbound to their entry, and should be replaced when
+    /// the store is re-keyed.
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Cipher::XSalsa20Poly1305)
+    }
+
+    /// Size of the nonces this cipher takes.
+    fn nonce_bytes(&self) -> usize {
+        match self {
+            Cipher::XSalsa20Poly1305 => backend::XSALSA20POLY1305_NONCE_BYTES,
+            Cipher::XChaCha20Poly1305 => backend::XCHACHA20POLY1305_NONCE_BYTES,
+            Cipher::Aes256Gcm => backend::AES256GCM_NONCE_BYTES,
+        }
+    }
+
+    /// Generates a random nonce of the size this cipher takes.
+    pub(crate) fn gen_nonce(&self) -> Vec<u8> {
+        backend::randombytes(self.nonce_bytes())
+    }
+
+    /// Checks that `key` and `nonce` can be used with this cipher.
+    fn check(&self, nonce: &[u8], key: &SecStr) -> Result<()> {
+        if key.unsecure().len() != backend::KEY_BYTES {
+            return Err(KVError {
+                error: ErrorType::CryptoError,
+                msg: Some("cannot derive key from password hash".to_string()),
+            });
+        }
+        if nonce.len() != self.nonce_bytes() {
+            return Err(KVError {
+                error: ErrorType::CryptoError,
+                msg: Some("value was stored with a nonce of the wrong size".to_string()),
+            });
+        }
+        if *self == Cipher::Aes256Gcm && !backend::aes256gcm_is_available() {
+            return Err(KVError {
+                error: ErrorType::CryptoError,
+                msg: Some("AES-256-GCM is not supported on this CPU".to_string()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Seals `msg` under `key`, binding it to `ad`.
+    pub(crate) fn seal(
+        &self,
+        msg: &[u8],
+        ad: &[u8],
+        nonce: &[u8],
+        key: &SecStr,
+    ) -> Result<Vec<u8>> {
+        self.check(nonce, key)?;
+        let key = key.unsecure();
+        let sealed = match self {
+            Cipher::XSalsa20Poly1305 => backend::xsalsa20poly1305_seal(msg, nonce, key),
+            Cipher::XChaCha20Poly1305 => backend::xchacha20poly1305_seal(msg, ad, nonce, key),
+            Cipher::Aes256Gcm => backend::aes256gcm_seal(msg, ad, nonce, key),
+        };
+        sealed.map_err(|_| KVError {
+            error: ErrorType::CryptoError,
+            msg: Some("cannot seal value".to_string()),
+        })
+    }
+
+    /// Opens `ciphertext` sealed under `key` and bound to `ad`.
+    pub(crate) fn open(
+        &self,
+        ciphertext: &[u8],
+        ad: &[u8],
+        nonce: &[u8],
+        key: &SecStr,
+    ) -> Result<Vec<u8>> {
+        self.check(nonce, key)?;
+        let key = key.unsecure();
+        match self {
+            Cipher::XSalsa20Poly1305 => backend::xsalsa20poly1305_open(ciphertext, nonce, key)
+                .map_err(|_| KVError {
+                    error: ErrorType::CryptoError,
+                    msg: Some("cannot validate value being decrypted".to_string()),
+                }),
+            Cipher::XChaCha20Poly1305 => {
+                backend::xchacha20poly1305_open(ciphertext, ad, nonce, key).map_err(|_| tampered())
+            }
+            Cipher::Aes256Gcm => {
+                backend::aes256gcm_open(ciphertext, ad, nonce, key).map_err(|_| tampered())
+            }
+        }
+    }
+}
+
+pub(crate) fn tampered() -> KVError {
+    KVError {
+        error: ErrorType::TamperError,
+        msg: Some("value was modified or moved from another entry".to_string()),
+    }
+}
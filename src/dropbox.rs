@@ -0,0 +1,76 @@
+//! Defines the drop box mode of a store. A drop box records an X25519 public key in its header,
+//! set with `MicroKV::with_public_key`, and every value is sealed to it as a sealed box. Anyone
+//! who can open the store, e.g. a CI job, can then `put` values, but only holders of the
+//! matching secret key, given with `MicroKV::with_secret_key`, can `get` them back.
+//!
+//! Sealed boxes take no associated data, so the namespace and key name a value is stored under
+//! are sealed along with it, and checked when it is opened.
+
+use secstr::{SecStr, SecVec};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::backend;
+use crate::cipher;
+use crate::errors::{ErrorType, KVError, Result};
+use crate::types::SealedValue;
+
+/// Size of the public and secret keys of a drop box.
+pub const KEY_BYTES: usize = 32;
+
+/// Generates the key pair of a new drop box, as its public key and memory-guarded secret key.
+pub fn gen_keypair() -> ([u8; KEY_BYTES], SecStr) {
+    let (public_key, secret_key) = backend::x25519_keypair();
+    (public_key, SecVec::new(secret_key))
+}
+
+/// Returns the public key matching `secret_key`.
+pub(crate) fn public_key(secret_key: &SecStr) -> Result<[u8; KEY_BYTES]> {
+    backend::x25519_public_key(secret_key.unsecure()).map_err(|_| KVError {
+        error: ErrorType::CryptoError,
+        msg: Some("secret key is not a valid X25519 key".to_string()),
+    })
+}
+
+/// Seals `value` to `public_key`, bound to `ad`.
+pub(crate) fn seal_value<V>(value: &V, public_key: &[u8], ad: &[u8]) -> Result<SealedValue>
+where
+    V: Serialize,
+{
+    let ser_val: Vec<u8> = bincode::serialize(&value).unwrap();
+    let msg = SecVec::new(bincode::serialize(&(ad, ser_val)).unwrap());
+    let sealed = backend::sealedbox_seal(msg.unsecure(), public_key).map_err(|_| KVError {
+        error: ErrorType::CryptoError,
+        msg: Some("cannot seal value to the public key of the store".to_string()),
+    })?;
+    // sealed boxes carry the ephemeral public key they were sealed with instead of a nonce
+    Ok(SealedValue::new(None, SecVec::new(sealed)))
+}
+
+/// Opens a value sealed by `seal_value` to `public_key`, with its secret key.
+pub(crate) fn open_value<V>(
+    value: &SealedValue,
+    public_key: &[u8],
+    secret_key: &SecStr,
+    ad: &[u8],
+) -> Result<V>
+where
+    V: DeserializeOwned + 'static,
+{
+    let msg = backend::sealedbox_open(value.data.unsecure(), public_key, secret_key.unsecure())
+        .map_err(|_| cipher::tampered())?;
+    let msg = SecVec::new(msg);
+    let (bound, ser_val): (Vec<u8>, Vec<u8>) =
+        bincode::deserialize(msg.unsecure()).map_err(|_| cipher::tampered())?;
+    if bound != ad {
+        return Err(cipher::tampered());
+    }
+    let value = bincode::deserialize(&ser_val).map_err(|e| KVError {
+        error: ErrorType::KVError,
+        msg: Some(format!(
+            "cannot deserialize into specified object type: {:?}",
+            e
+        )),
+    })?;
+    Ok(value)
+}
@@ -0,0 +1,142 @@
+//! Defines how values are encoded before they are sealed. A sealed value is as long as its
+//! plaintext, so that a 16-character API key and a PEM certificate are easy to tell apart in the
+//! store file; `MicroKV::with_padding` pads values to size buckets, hiding their length up to
+//! the bucket. Large values, such as JSON blobs, can also be compressed with
+//! `MicroKV::with_compression`.
+//!
+//! Both settings are recorded in the store header, and only apply to values written afterwards.
+//! The `Encoding` of each value is kept alongside it and bound to it like its entry, so that
+//! stores mixing encodings keep decoding.
+//!
+//! Compressing a secret along with data an attacker controls can leak it through the length of
+//! the result, as in CRIME, so compression is best kept for values written by a single party.
+
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{ErrorType, KVError, Result};
+
+/// Size below which values are not compressed, as deflate rarely makes them smaller.
+const COMPRESSION_THRESHOLD: usize = 256;
+
+/// Smallest bucket of `Padding::PowerOfTwo`.
+const MIN_BUCKET: usize = 64;
+
+/// Marks the end of a padded value, followed by zeros up to the bucket size, as in
+/// ISO/IEC 7816-4.
+const PADDING_MARKER: u8 = 0x80;
+
+/// Size buckets values are padded to before they are sealed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Padding {
+    /// values are sealed at their own length
+    #[default]
+    None,
+
+    /// values are padded to the next power of two, of at least 64 bytes, which hides their
+    /// length up to a factor of two
+    PowerOfTwo,
+
+    /// values are padded to the next multiple of the given number of bytes
+    Block(u32),
+}
+
+impl Padding {
+    /// Size a value of `len` bytes is padded to, counting its end marker.
+    fn bucket(&self, len: usize) -> usize {
+        match self {
+            Padding::None => len,
+            Padding::PowerOfTwo => (len + 1).max(MIN_BUCKET).next_power_of_two(),
+            Padding::Block(size) => {
+                let size = (*size as usize).max(1);
+                (len + 1).div_ceil(size) * size
+            }
+        }
+    }
+}
+
+/// Compression applied to values before they are padded and sealed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Compression {
+    /// values are sealed as they are
+    #[default]
+    None,
+
+    /// values of 256 bytes or more are compressed with deflate, when it makes them smaller
+    Deflate,
+}
+
+/// How a value was encoded before it was sealed, kept alongside it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Encoding {
+    /// compression the value was written with, `Compression::None` if it was not compressed
+    pub compression: Compression,
+
+    /// whether the value was padded to a size bucket
+    pub is_padded: bool,
+}
+
+impl Encoding {
+    /// Appends the encoding to the associated data `ad` of a value, so that it can't be changed
+    /// without the value failing to open. Values sealed without encoding keep `ad` as it is.
+    pub(crate) fn bind(&self, mut ad: Vec<u8>) -> Vec<u8> {
+        if *self != Encoding::default() {
+            ad.extend(bincode::serialize(self).unwrap());
+        }
+        ad
+    }
+}
+
+/// Compresses and pads `data` as configured, and returns it with the encoding it ended up with.
+pub(crate) fn encode(
+    data: &[u8],
+    padding: Padding,
+    compression: Compression,
+) -> Result<(Vec<u8>, Encoding)> {
+    let mut encoding = Encoding::default();
+    let mut data = data.to_vec();
+    if compression == Compression::Deflate && data.len() >= COMPRESSION_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < data.len() {
+            data = compressed;
+            encoding.compression = Compression::Deflate;
+        }
+    }
+    if padding != Padding::None {
+        let bucket = padding.bucket(data.len());
+        data.push(PADDING_MARKER);
+        data.resize(bucket, 0);
+        encoding.is_padded = true;
+    }
+    Ok((data, encoding))
+}
+
+/// Reverses `encode`, given the encoding recorded alongside the value.
+pub(crate) fn decode(mut data: Vec<u8>, encoding: Encoding) -> Result<Vec<u8>> {
+    if encoding.is_padded {
+        match data.iter().rposition(|byte| *byte != 0) {
+            Some(end) if data[end] == PADDING_MARKER => data.truncate(end),
+            _ => return Err(invalid_encoding("padded")),
+        }
+    }
+    if encoding.compression == Compression::Deflate {
+        let mut decompressed = Vec::new();
+        DeflateDecoder::new(&data[..])
+            .read_to_end(&mut decompressed)
+            .map_err(|_| invalid_encoding("compressed"))?;
+        data = decompressed;
+    }
+    Ok(data)
+}
+
+fn invalid_encoding(encoding: &str) -> KVError {
+    KVError {
+        error: ErrorType::KVError,
+        msg: Some(format!("value is not validly {}", encoding)),
+    }
+}
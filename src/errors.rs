@@ -13,7 +13,13 @@ pub enum ErrorType {
     Custom,                       // custom error
     KVError,                      // issues involving database interactions
     CryptoError,                  // problems arisen from performing authentication encryption
+    InvalidPassword,              // password or key given does not unlock the store
+    ProviderError,                // key could not be read from its provider
+    TamperError,                  // value was modified or moved to another entry
+    IntegrityError,               // store was modified, truncated or rolled back outside of microkv
     FileError,                    // unified type for io::Error
+    LockError,                    // store file is locked by another process
+    ReadOnly,                     // store was opened read-only and can't be changed
     PoisonError,                  // locking error, indicating poisoned mutex
     MigrateError(String, String), // Migrate to new microkv database
 }
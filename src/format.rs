@@ -0,0 +1,187 @@
+//! Defines the container format of store files, so that they can be recognized and inspected
+//! without being opened. A store file is laid out as:
+//!
+//! | offset | size | field                                                      |
+//! |--------|------|------------------------------------------------------------|
+//! | 0      | 8    | magic bytes, `\x89MICROKV`                                 |
+//! | 8      | 2    | format version, little-endian, currently 1                 |
+//! | 10     | 4    | length `n` of the header, little-endian                    |
+//! | 14     | `n`  | `Header`, serialized with `bincode`                        |
+//! | 14 + n | rest | body: the store, serialized with `bincode`                 |
+//!
+//! The header repeats the layout version, cipher, KDF and flags of the store for tools; the
+//! body, which is authenticated, is what the store is opened with. Neither records the path of
+//! the store, so store files can be copied and moved freely.
+//!
+//! Stores written before the container format are a bare `bincode` dump of the store, which
+//! starts with its layout version from 0.3.0 on, and with its path before.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cipher::Cipher;
+use crate::errors::{ErrorType, KVError, Result};
+use crate::kdf::Kdf;
+use crate::keyring::Keyring;
+
+/// Bytes every store file starts with. The first is not ASCII, so that a store file is not
+/// taken for text.
+pub const MAGIC: &[u8; 8] = b"\x89MICROKV";
+
+/// Version of the container format written by this version of microkv.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Size of the magic bytes, format version and header length.
+const PREFIX_BYTES: usize = 14;
+
+/// The store is encrypted.
+pub const FLAG_ENCRYPTED: u32 = 1;
+
+/// Namespace and key names are blinded.
+pub const FLAG_BLIND_NAMES: u32 = 1 << 1;
+
+/// The store is a public-key drop box.
+pub const FLAG_DROP_BOX: u32 = 1 << 2;
+
+/// Values written from now on are padded.
+pub const FLAG_PADDED: u32 = 1 << 3;
+
+/// Values written from now on are compressed.
+pub const FLAG_COMPRESSED: u32 = 1 << 4;
+
+/// Header of a store file, describing the store held in its body.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    /// version of the layout of the body, such as "0.4.0"
+    pub store_version: String,
+
+    /// cipher values are sealed with
+    pub cipher: Cipher,
+
+    /// key derivation function of new password slots
+    pub kdf: Kdf,
+
+    /// `FLAG_*` bits set for the store
+    pub flags: u32,
+}
+
+impl Header {
+    /// Header describing a store of layout `store_version` with `keyring`.
+    pub(crate) fn new(store_version: &str, keyring: &Keyring) -> Self {
+        let flags = [
+            (keyring.is_encrypted(), FLAG_ENCRYPTED),
+            (keyring.blind_names, FLAG_BLIND_NAMES),
+            (keyring.public_key.is_some(), FLAG_DROP_BOX),
+            (keyring.padding != Default::default(), FLAG_PADDED),
+            (keyring.compression != Default::default(), FLAG_COMPRESSED),
+        ];
+        Self {
+            store_version: store_version.to_string(),
+            cipher: keyring.cipher,
+            kdf: keyring.kdf.clone(),
+            flags: flags
+                .iter()
+                .filter(|(is_set, _)| *is_set)
+                .fold(0, |flags, (_, flag)| flags | flag),
+        }
+    }
+}
+
+/// Reads the header of the store file at `path`, `None` if it was written before the container
+/// format.
+pub fn read_header(path: &Path) -> Result<Option<Header>> {
+    let mut prefix = Vec::new();
+    File::open(path)?
+        .take(PREFIX_BYTES as u64)
+        .read_to_end(&mut prefix)?;
+    let len = match header_len(&prefix)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let mut bytes = Vec::new();
+    File::open(path)?
+        .take((PREFIX_BYTES + len) as u64)
+        .read_to_end(&mut bytes)?;
+    split(&bytes).map(|split| split.map(|(header, _)| header))
+}
+
+/// Lays out a store file holding `body` under `header`.
+pub(crate) fn join<S: Serialize>(header: &Header, body: &S) -> Result<Vec<u8>> {
+    let header = bincode::serialize(header).map_err(invalid)?;
+    let body = bincode::serialize(body).map_err(invalid)?;
+    let mut bytes = Vec::with_capacity(PREFIX_BYTES + header.len() + body.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    bytes.extend(header);
+    bytes.extend(body);
+    Ok(bytes)
+}
+
+/// Splits a store file into its header and body, `None` if it was written before the container
+/// format.
+pub(crate) fn split(bytes: &[u8]) -> Result<Option<(Header, &[u8])>> {
+    let len = match header_len(bytes)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let header = bytes
+        .get(PREFIX_BYTES..PREFIX_BYTES + len)
+        .ok_or_else(|| file_error("store file is truncated"))?;
+    let header = bincode::deserialize(header).map_err(invalid)?;
+    Ok(Some((header, &bytes[PREFIX_BYTES + len..])))
+}
+
+/// Length of the header, from the first bytes of a store file.
+fn header_len(prefix: &[u8]) -> Result<Option<usize>> {
+    if !prefix.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    if prefix.len() < PREFIX_BYTES {
+        return Err(file_error("store file is truncated"));
+    }
+    let version = u16::from_le_bytes([prefix[8], prefix[9]]);
+    if version > FORMAT_VERSION {
+        return Err(file_error(&format!(
+            "store file is of format {}, written by a newer microkv",
+            version
+        )));
+    }
+    let len = u32::from_le_bytes([prefix[10], prefix[11], prefix[12], prefix[13]]);
+    Ok(Some(len as usize))
+}
+
+/// Layout version a store file written before the container format starts with, `None` for
+/// stores written before 0.3.0, which start with their path instead.
+pub(crate) fn legacy_version(bytes: &[u8]) -> Option<String> {
+    let mut len = [0; 8];
+    len.copy_from_slice(bytes.get(..8)?);
+    let len = u64::from_le_bytes(len) as usize;
+    // a layout version is short, unlike most paths
+    if len > 16 {
+        return None;
+    }
+    let version = std::str::from_utf8(bytes.get(8..8 + len)?).ok()?;
+    let is_version = !version.is_empty()
+        && version
+            .split('.')
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()));
+    match is_version {
+        true => Some(version.to_string()),
+        false => None,
+    }
+}
+
+fn invalid(e: bincode::Error) -> KVError {
+    file_error(&format!("store file is not valid: {:?}", e))
+}
+
+fn file_error(msg: &str) -> KVError {
+    KVError {
+        error: ErrorType::FileError,
+        msg: Some(msg.to_string()),
+    }
+}
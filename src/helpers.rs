@@ -1,14 +1,17 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 
+use crate::backend;
+use crate::cipher::Cipher;
+use crate::errors::{ErrorType, KVError, Result};
+use crate::types::{Nonce, SealedValue};
 use secstr::{SecStr, SecVec};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use sodiumoxide::crypto::secretbox::Nonce;
-use sodiumoxide::crypto::secretbox::{self, Key};
-
-use crate::errors::{ErrorType, KVError, Result};
 
 /// Defines the directory path where a key-value store
 /// (or multiple) can be interacted with.
@@ -56,64 +59,129 @@ where
 
 /// gen nonce
 pub fn gen_nonce() -> Nonce {
-    secretbox::gen_nonce()
+    Nonce::generate()
+}
+
+/// encode value, sealing it under a freshly generated nonce
+pub fn encode_value<V>(value: &V, pwd: &Option<SecStr>) -> Result<SealedValue>
+where
+    V: Serialize,
+{
+    encode_value_with_nonce(value, pwd, &gen_nonce())
+}
+
+/// encode value with a given nonce. A nonce must never be reused under the same password,
+/// so this should only be called directly when reproducing values of older stores.
+pub fn encode_value_with_nonce<V>(
+    value: &V,
+    pwd: &Option<SecStr>,
+    nonce: &Nonce,
+) -> Result<SealedValue>
+where
+    V: Serialize,
+{
+    seal_value_with_nonce(value, pwd, Cipher::XSalsa20Poly1305, &[], &nonce.0)
 }
 
-/// encode value
-pub fn encode_value<V>(value: &V, pwd: &Option<SecStr>, nonce: &Nonce) -> Result<SecVec<u8>>
+/// decode value sealed by `encode_value`
+pub fn decode_value<V>(value: &SealedValue, pwd: &Option<SecStr>) -> Result<V>
+where
+    V: DeserializeOwned + 'static,
+{
+    open_value(value, pwd, Cipher::XSalsa20Poly1305, &[])
+}
+
+/// Associated data binding a value to the entry it is stored under.
+pub fn associated_data(namespace: &str, key: &str) -> Vec<u8> {
+    let mut ad: Vec<u8> = Vec::with_capacity(8 + namespace.len() + key.len());
+    ad.extend_from_slice(&(namespace.len() as u64).to_le_bytes());
+    ad.extend_from_slice(namespace.as_bytes());
+    ad.extend_from_slice(key.as_bytes());
+    ad
+}
+
+/// Blinds a name with a keyed hash, so that it can be looked up without being stored in clear.
+/// `domain` keeps namespace and key names apart.
+pub fn blind_name(key: &SecStr, domain: &[u8], name: &[u8]) -> String {
+    let mut msg: Vec<u8> = domain.to_vec();
+    msg.push(0);
+    msg.extend_from_slice(name);
+    backend::hmac_sha256(key.unsecure(), &msg)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// encode value with `cipher` under a freshly generated nonce, binding it to `ad`
+pub fn seal_value<V>(
+    value: &V,
+    pwd: &Option<SecStr>,
+    cipher: Cipher,
+    ad: &[u8],
+) -> Result<SealedValue>
+where
+    V: Serialize,
+{
+    seal_value_with_nonce(value, pwd, cipher, ad, &cipher.gen_nonce())
+}
+
+fn seal_value_with_nonce<V>(
+    value: &V,
+    pwd: &Option<SecStr>,
+    cipher: Cipher,
+    ad: &[u8],
+    nonce: &[u8],
+) -> Result<SealedValue>
 where
     V: Serialize,
 {
     // serialize the object for committing to db
     let ser_val: Vec<u8> = bincode::serialize(&value).unwrap();
     // encrypt and secure value if password is available
-    let value: SecVec<u8> = match pwd {
+    let value: SealedValue = match pwd {
         // encrypt using AEAD and secure memory
-        Some(pwd) => {
-            let key: Key = Key::from_slice(pwd.unsecure()).unwrap();
-            SecVec::new(secretbox::seal(&ser_val, nonce, &key))
-        }
+        Some(pwd) => SealedValue::new(
+            Some(nonce.to_vec()),
+            SecVec::new(cipher.seal(&ser_val, ad, nonce, pwd)?),
+        ),
 
         // otherwise initialize secure serialized object to insert to BTreeMap
-        None => SecVec::new(ser_val),
+        None => SealedValue::new(None, SecVec::new(ser_val)),
     };
     Ok(value)
 }
 
-/// decode value
-pub fn decode_value<V>(value: &SecVec<u8>, pwd: &Option<SecStr>, nonce: &Nonce) -> Result<V>
+/// decode value sealed by `seal_value` with the same cipher and associated data
+pub fn open_value<V>(
+    value: &SealedValue,
+    pwd: &Option<SecStr>,
+    cipher: Cipher,
+    ad: &[u8],
+) -> Result<V>
 where
     V: DeserializeOwned + 'static,
 {
     // get value to deserialize. If password is set, retrieve the value, and decrypt it
-    // using AEAD. Otherwise just get the value and return
+    // using AEAD with the nonce stored alongside it. Otherwise just get the value and return
     let deser_val = match pwd {
         Some(pwd) => {
-            // initialize key from pwd slice
-            let key = match Key::from_slice(pwd.unsecure()) {
-                Some(k) => k,
+            // an encrypted value is always stored with the nonce it was sealed with
+            let nonce = match value.nonce {
+                Some(ref n) => n,
                 None => {
                     return Err(KVError {
                         error: ErrorType::CryptoError,
-                        msg: Some("cannot derive key from password hash".to_string()),
+                        msg: Some("value was not stored with a nonce".to_string()),
                     });
                 }
             };
 
             // borrow secured value by reference, and decrypt before deserializing
-            match secretbox::open(value.unsecure(), nonce, &key) {
-                Ok(r) => r,
-                Err(_) => {
-                    return Err(KVError {
-                        error: ErrorType::CryptoError,
-                        msg: Some("cannot validate value being decrypted".to_string()),
-                    });
-                }
-            }
+            cipher.open(value.data.unsecure(), ad, nonce, pwd)?
         }
 
         // if no password, return value as-is
-        None => value.unsecure().to_vec(),
+        None => value.data.unsecure().to_vec(),
     };
 
     // finally deserialize into deserializable object to return as
@@ -128,16 +196,26 @@ where
 }
 
 /// Writes the IndexMap to persistent storage after encrypting with secure crypto construction.
+///
+/// The store is written to a temporary file next to it, synced to disk, and renamed over the
+/// previous one before the directory is synced, so that a crash or power loss at any point
+/// leaves either the old or the new store intact, never a mix of both.
 pub(crate) fn persist_serialize<S>(path: &Path, object: &S) -> Result<()>
 where
     S: Serialize,
 {
+    persist(path, &bincode::serialize(object).unwrap())
+}
+
+/// Writes `data` to the store file at `path`, atomically as `persist_serialize` does.
+pub(crate) fn persist(path: &Path, data: &[u8]) -> Result<()> {
     // initialize workspace directory if not exists
-    match path.parent() {
-        Some(path) => {
-            if !path.is_dir() {
-                std::fs::create_dir_all(path)?;
+    let dir = match path.parent() {
+        Some(dir) => {
+            if !dir.is_dir() {
+                fs::create_dir_all(dir)?;
             }
+            dir
         }
         None => {
             return Err(KVError {
@@ -145,15 +223,84 @@ where
                 msg: Some("The store file parent path isn't sound".to_string()),
             });
         }
+    };
+
+    let tmp_path = temp_path(path);
+    let result = write_synced(&tmp_path, path, data)
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .and_then(|_| sync_dir(dir));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
     }
+    Ok(result?)
+}
 
-    // check if path to db exists, if not create it
-    let path = Path::new(path);
-    let mut file: File = OpenOptions::new().write(true).create(true).open(path)?;
+/// Sibling of `path` a commit is written to before it replaces the store, unique to this
+/// process and commit so that concurrent commits don't write into each other.
+fn temp_path(path: &Path) -> PathBuf {
+    static COMMITS: AtomicUsize = AtomicUsize::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        process::id(),
+        COMMITS.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Writes `data` to a new file at `path` and syncs it to disk, with the permissions of the
+/// store at `target` if it exists.
+fn write_synced(path: &Path, target: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file: File = OpenOptions::new().write(true).create_new(true).open(path)?;
+    if let Ok(metadata) = fs::metadata(target) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Syncs a directory, so that a file renamed into it survives a power loss. Windows does not
+/// allow opening directories, and persists renames on its own.
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
 
-    // acquire a file lock that unlocks at the end of scope
-    // let _file_lock = Arc::new(Mutex::new(0));
-    let ser = bincode::serialize(object).unwrap();
-    file.write_all(&ser)?;
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> io::Result<()> {
     Ok(())
 }
+
+/// Metadata of a file that changes whenever it is written or replaced, so that a file can be
+/// told apart from the one last read without reading it again.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+
+    /// device, inode and change time, which also change when a file is replaced by a rename
+    /// or written twice within the resolution of its modification time
+    #[cfg(unix)]
+    inode: (u64, u64, i64, i64),
+}
+
+impl FileStamp {
+    /// Stamp of the file at `path`, `None` if there is none.
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            #[cfg(unix)]
+            inode: {
+                use std::os::unix::fs::MetadataExt;
+                (
+                    metadata.dev(),
+                    metadata.ino(),
+                    metadata.ctime(),
+                    metadata.ctime_nsec(),
+                )
+            },
+        })
+    }
+}
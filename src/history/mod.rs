@@ -1,5 +1,7 @@
 pub use self::v030::*;
+pub use self::v040::*;
 pub use self::vless030::*;
 
 mod v030;
+mod v040;
 mod vless030;
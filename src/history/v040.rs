@@ -0,0 +1,1106 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use secstr::{SecStr, SecVec};
+use serde::{Deserialize, Serialize};
+
+use crate::backend;
+use crate::cipher::Cipher;
+use crate::dropbox;
+use crate::encoding;
+use crate::errors::{ErrorType, KVError, Result};
+use crate::format::{self, Header};
+use crate::helpers::{self, FileStamp};
+use crate::integrity::{self, Integrity};
+use crate::kdf::Kdf;
+use crate::keyring::{Credential, Keyring, SlotKind};
+use crate::lock::{FileLock, LockMode};
+use crate::merge::{self, Conflict, ConflictHandler, ConflictPolicy, Dirty};
+use crate::types::{SealedValue, Storage, KV};
+use crate::wal::{self, LogEntry, LogHeader, LogRecord};
+
+/// Borrowed view of a `MicroKV040` that serializes exactly like it, so that a commit writes out
+/// the same state it authenticated while holding every lock.
+#[derive(Serialize)]
+struct CommitView<'a> {
+    version: &'a String,
+    storage: HashMap<&'a String, &'a KV>,
+    keyring: &'a Keyring,
+    integrity: &'a Integrity,
+    is_auto_commit: bool,
+}
+
+/// Canonical encoding of the state authenticated by the MAC. Namespaces must be sorted, as the
+/// storage map iterates in no particular order.
+fn digest(
+    version: &str,
+    integrity: (&[u8], u64),
+    keyring: &Keyring,
+    namespaces: &[(&String, &KV)],
+) -> Result<Vec<u8>> {
+    bincode::serialize(&(version, integrity, keyring, namespaces)).map_err(|e| KVError {
+        error: ErrorType::KVError,
+        msg: Some(format!("cannot serialize store digest: {:?}", e)),
+    })
+}
+
+/// Key of the entry holding the original name of a namespace, in stores that blind names.
+/// Blinded key names are never empty, so it can't collide with one.
+pub(crate) const NAMESPACE_ENTRY: &str = "";
+
+/// The MicroKV class version 0.4.0
+/// Defines the main interface structure to represent the most
+/// recent state of the data store.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MicroKV040 {
+    /// The version of persist data. this field will help migrate
+    version: String,
+    /// path of the store file, which is not recorded in it so that it can be moved
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) path: PathBuf,
+
+    /// stores the actual key-value store encapsulated with a RwLock
+    pub(crate) storage: Arc<RwLock<HashMap<String, Storage>>>,
+
+    /// key slots, key check block and memory-guarded data key, shared between handles
+    pub(crate) keyring: Arc<RwLock<Keyring>>,
+
+    /// generation counter and MAC over the whole store
+    pub(crate) integrity: Arc<RwLock<Integrity>>,
+
+    /// is auto commit
+    pub(crate) is_auto_commit: bool,
+
+    /// how reloads and commits wait for other processes locking the store file
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) lock_mode: LockMode,
+
+    /// whether auto-commits of single entries are appended to the write-ahead log
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) write_ahead_log: bool,
+
+    /// entries changed by this handle and not committed yet, always locked last
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) dirty: Arc<RwLock<Dirty>>,
+
+    /// how reloads settle entries changed both here and by another handle
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) conflict_policy: ConflictPolicy,
+
+    /// called with every conflict a reload settles
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) conflict_handler: Option<ConflictHandler>,
+
+    /// whether the store was opened read-only, see `OpenOptions::read_only`
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) read_only: bool,
+}
+
+impl MicroKV040 {
+    pub fn create(
+        path: PathBuf,
+        kdf: Kdf,
+        pwd: Option<SecStr>,
+        is_auto_commit: bool,
+        storage: Arc<RwLock<HashMap<String, Storage>>>,
+    ) -> Self {
+        Self {
+            version: "0.4.0".to_string(),
+            path,
+            storage,
+            keyring: Arc::new(RwLock::new(Keyring::new(kdf, pwd))),
+            integrity: Arc::new(RwLock::new(Integrity::new())),
+            is_auto_commit,
+            lock_mode: LockMode::default(),
+            write_ahead_log: false,
+            dirty: Arc::new(RwLock::new(Dirty::new())),
+            conflict_policy: ConflictPolicy::default(),
+            conflict_handler: None,
+            read_only: false,
+        }
+    }
+}
+
+impl MicroKV040 {
+    pub fn version(&self) -> &String {
+        &self.version
+    }
+
+    /// Seals a value with the cipher of the store, bound to the entry it is stored under. Values
+    /// of a namespace with a key of its own are sealed under that key, and values of a drop box
+    /// to its public key.
+    pub fn encode_value<V>(
+        &self,
+        namespace: impl AsRef<str>,
+        key: impl AsRef<str>,
+        value: &V,
+    ) -> Result<SealedValue>
+    where
+        V: Serialize,
+    {
+        let (namespace, key) = (namespace.as_ref(), key.as_ref());
+        let keyring = self.keyring()?;
+        // all data serialize to serde_json::Value
+        let value = serde_json::to_value(value)?.to_string();
+        let (value, encoding) =
+            encoding::encode(value.as_bytes(), keyring.padding, keyring.compression)?;
+        let ad = encoding.bind(helpers::associated_data(namespace, key));
+        let mut value = match keyring.drop_box(key) {
+            Some(public_key) => {
+                keyring.ensure_unlocked()?;
+                dropbox::seal_value(&value, public_key, &ad)?
+            }
+            None => {
+                let (pwd, cipher) = keyring.value_key(namespace, key)?;
+                helpers::seal_value(&value, &pwd, cipher, &ad)?
+            }
+        };
+        value.encoding = encoding;
+        Ok(value)
+    }
+
+    /// Opens a value sealed by `encode_value` for the same entry. Fails with
+    /// `ErrorType::TamperError` if it was modified or moved from another entry, and with
+    /// `ErrorType::InvalidPassword` if its namespace has a key of its own and is locked, or if
+    /// the store is a drop box and its secret key was not given.
+    pub fn decode_value(
+        &self,
+        namespace: impl AsRef<str>,
+        key: impl AsRef<str>,
+        value: &SealedValue,
+    ) -> Result<serde_json::Value> {
+        let (namespace, key) = (namespace.as_ref(), key.as_ref());
+        let keyring = self.keyring()?;
+        let ad = value
+            .encoding
+            .bind(helpers::associated_data(namespace, key));
+        // values are serialized as strings, which bincode encodes like raw bytes
+        let data: Vec<u8> = match keyring.drop_box(key) {
+            Some(public_key) => {
+                keyring.ensure_unlocked()?;
+                dropbox::open_value(value, public_key, keyring.secret_key()?, &ad)?
+            }
+            None => {
+                let (pwd, cipher) = keyring.value_key(namespace, key)?;
+                helpers::open_value(value, &pwd, cipher, &ad)?
+            }
+        };
+        let data = encoding::decode(data, value.encoding)?;
+        let value = serde_json::from_slice(&data)?;
+        Ok(value)
+    }
+
+    /// Whether namespace and key names are stored blinded.
+    pub(crate) fn blinds_names(&self) -> Result<bool> {
+        Ok(self.keyring()?.names_key()?.is_some())
+    }
+
+    /// Name `namespace` is stored under, blinded if the store blinds names.
+    pub(crate) fn storage_namespace(&self, namespace: impl AsRef<str>) -> Result<String> {
+        let namespace = namespace.as_ref();
+        match self.keyring()?.names_key()? {
+            Some(key) => Ok(helpers::blind_name(
+                &key,
+                b"namespace",
+                namespace.as_bytes(),
+            )),
+            None => Ok(namespace.to_string()),
+        }
+    }
+
+    /// Name `key` of `namespace` is stored under, blinded if the store blinds names.
+    pub(crate) fn storage_key(
+        &self,
+        namespace: impl AsRef<str>,
+        key: impl AsRef<str>,
+    ) -> Result<String> {
+        let key = key.as_ref();
+        match self.keyring()?.names_key()? {
+            Some(names_key) => {
+                let name = helpers::associated_data(namespace.as_ref(), key);
+                Ok(helpers::blind_name(&names_key, b"key", &name))
+            }
+            None => Ok(key.to_string()),
+        }
+    }
+
+    /// Seals the original name of the entry `value` is stored under, if the store blinds names.
+    /// `namespace` and `key` are the names it is stored under.
+    pub(crate) fn seal_name(
+        &self,
+        namespace: &str,
+        key: &str,
+        name: &str,
+        value: &mut SealedValue,
+    ) -> Result<()> {
+        let keyring = self.keyring()?;
+        if let Some(names_key) = keyring.names_key()? {
+            let ad = helpers::associated_data(namespace, key);
+            let name = helpers::seal_value(&name, &Some(names_key), keyring.cipher, &ad)?;
+            value.name = Some(Box::new(name));
+        }
+        Ok(())
+    }
+
+    /// Entry holding the original name of a namespace stored under `namespace`, if the store
+    /// blinds names.
+    pub(crate) fn namespace_entry(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Result<Option<SealedValue>> {
+        match self.keyring()?.names_key()? {
+            Some(_) => Ok(Some(self.encode_value(
+                namespace,
+                NAMESPACE_ENTRY,
+                &name,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Original names of the entries of `data`, stored under `namespace`.
+    pub(crate) fn entry_names(&self, namespace: &str, data: &KV) -> Result<Vec<String>> {
+        let keyring = self.keyring()?;
+        let names_key = match keyring.names_key()? {
+            Some(key) => key,
+            None => return Ok(data.keys().cloned().collect()),
+        };
+        let mut names = Vec::new();
+        for (key, value) in data.iter() {
+            if key == NAMESPACE_ENTRY {
+                continue;
+            }
+            let name = match value.name {
+                Some(ref name) => name,
+                None => {
+                    return Err(KVError {
+                        error: ErrorType::TamperError,
+                        msg: Some("entry was stored without its name".to_string()),
+                    })
+                }
+            };
+            let ad = helpers::associated_data(namespace, key);
+            let key = Some(names_key.clone());
+            names.push(helpers::open_value(name, &key, keyring.cipher, &ad)?);
+        }
+        Ok(names)
+    }
+
+    pub(crate) fn keyring(&self) -> Result<RwLockReadGuard<'_, Keyring>> {
+        self.keyring.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })
+    }
+
+    pub(crate) fn keyring_mut(&self) -> Result<RwLockWriteGuard<'_, Keyring>> {
+        self.keyring.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })
+    }
+
+    /// Fails with `ErrorType::ReadOnly` if the store was opened read-only.
+    pub(crate) fn ensure_writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(KVError {
+                error: ErrorType::ReadOnly,
+                msg: Some(format!("store {:?} was opened read-only", self.path)),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Unlocks the store with `credential`, failing if it opens none of the key slots.
+    /// Returns the position of the slot it opened.
+    ///
+    /// A store migrated from 0.3.0 without its password is sealed again by `upgrade_legacy`,
+    /// unless the handle is read-only.
+    pub(crate) fn unlock(&self, credential: &Credential) -> Result<usize> {
+        self.reload()?;
+        let fallback = match self.keyring()?.needs_fallback() {
+            true => self.any_value()?,
+            false => None,
+        };
+        let index = self.keyring_mut()?.unlock(credential, fallback)?;
+        if let Err(e) = self.verify_file() {
+            self.keyring_mut()?.lock();
+            return Err(e);
+        }
+        if self.keyring()?.cipher.is_legacy() && !self.read_only {
+            return self.upgrade_legacy(index, credential);
+        }
+        Ok(index)
+    }
+
+    /// Seals every value of a store migrated from 0.3.0 again, once `credential` has opened
+    /// its slot at `index`: under a new random data key, each with a fresh nonce instead of the
+    /// store-wide one, and with the default cipher, which binds it to its entry. The slot is
+    /// moved onto Argon2id. The store is committed, unless its file is still in the layout of
+    /// 0.3.0, as with `MigrateMode::Explicit`, which the next commit of the handle replaces.
+    /// Returns the position of the new slot.
+    fn upgrade_legacy(&self, index: usize, credential: &Credential) -> Result<usize> {
+        let (old, kdf) = {
+            let keyring = self.keyring()?;
+            let kdf = match &keyring.slots[index].kind {
+                SlotKind::Password(kdf) | SlotKind::Keyfile(kdf) => kdf.renew(),
+                SlotKind::Hash | SlotKind::Wrapper { .. } => keyring.kdf.renew(),
+            };
+            (keyring.pwd()?.clone(), kdf)
+        };
+        self.rekey(&old, credential, kdf)?;
+        if let Ok(Some(_)) = format::read_header(&self.path) {
+            self.commit()?;
+        }
+        Ok(0)
+    }
+
+    /// Unlocks `namespace`, as stored, with `credential`. A namespace without a key of its own
+    /// is given one for `credential`: its values are sealed again under a new random data key,
+    /// and the store is committed. Returns the position of the slot `credential` opened.
+    pub(crate) fn unlock_namespace(
+        &self,
+        namespace: &str,
+        credential: &Credential,
+    ) -> Result<usize> {
+        if let Some(keyring) = self.keyring_mut()?.namespaces.get_mut(namespace) {
+            return keyring.unlock(credential, None);
+        }
+
+        self.ensure_writable()?;
+        self.keyring()?.ensure_unlocked()?;
+        self.safe_storage(namespace)?;
+        let storage_map = self.storage.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let storage = storage_map.get(namespace).unwrap();
+        let mut data = storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let mut keyring = self.keyring_mut()?;
+        let mut namespace_keyring = Keyring::new(keyring.kdf.renew(), None);
+        if !keyring.cipher.is_legacy() {
+            namespace_keyring.cipher = keyring.cipher;
+        }
+        let index = namespace_keyring.unlock(credential, None)?;
+
+        let (old, old_cipher) = (keyring.pwd()?.clone(), keyring.cipher);
+        let new = namespace_keyring.pwd.clone();
+        let mut resealed = KV::new();
+        for (key, sealed) in data.iter() {
+            // the name of a blinded namespace stays under the store key, and values of a drop
+            // box stay sealed to its public key
+            if (keyring.blind_names && key == NAMESPACE_ENTRY) || keyring.drop_box(key).is_some() {
+                resealed.insert(key.clone(), sealed.clone());
+                continue;
+            }
+            let ad = sealed
+                .encoding
+                .bind(helpers::associated_data(namespace, key));
+            let value: Vec<u8> = helpers::open_value(sealed, &old, old_cipher, &ad)?;
+            let mut value = helpers::seal_value(&value, &new, namespace_keyring.cipher, &ad)?;
+            value.name = sealed.name.clone();
+            value.encoding = sealed.encoding;
+            value.version = sealed.version;
+            resealed.insert(key.clone(), value);
+        }
+        keyring
+            .namespaces
+            .insert(namespace.to_string(), namespace_keyring);
+        *data = resealed;
+        drop(keyring);
+        drop(data);
+        drop(storage_map);
+        self.commit()?;
+        Ok(index)
+    }
+
+    pub(crate) fn integrity(&self) -> Result<RwLockReadGuard<'_, Integrity>> {
+        self.integrity.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })
+    }
+
+    pub(crate) fn integrity_mut(&self) -> Result<RwLockWriteGuard<'_, Integrity>> {
+        self.integrity.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })
+    }
+
+    pub(crate) fn dirty_mut(&self) -> Result<RwLockWriteGuard<'_, Dirty>> {
+        self.dirty.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })
+    }
+
+    /// Marks `key` of `namespace`, as stored, as changed by this handle and not committed yet,
+    /// with the version it has in `data` before it is changed. Called by writers while they
+    /// hold the lock on `data`.
+    pub(crate) fn mark_dirty(&self, namespace: &str, key: &str, data: &KV) -> Result<()> {
+        self.dirty_mut()?
+            .entry(namespace.to_string())
+            .or_default()
+            .entry(key.to_string())
+            .or_insert_with(|| data.get(key).map(|value| value.version).unwrap_or(0));
+        Ok(())
+    }
+
+    /// Reads the store file at `path`, which must be a container of this layout.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        match format::split(&bytes)? {
+            Some((header, body)) if header.store_version == "0.4.0" => Self::decode(path, body),
+            _ => Err(KVError {
+                error: ErrorType::FileError,
+                msg: Some(format!("{:?} is not a store of version 0.4.0", path)),
+            }),
+        }
+    }
+
+    /// Deserializes the body of the store file at `path`.
+    pub(crate) fn decode(path: &Path, body: &[u8]) -> Result<Self> {
+        let mut kv: Self = bincode::deserialize(body).map_err(|e| KVError {
+            error: ErrorType::FileError,
+            msg: Some(format!("cannot deserialize store {:?}: {:?}", path, e)),
+        })?;
+        kv.path = path.to_path_buf();
+        Ok(kv)
+    }
+
+    /// Checks the integrity of the store file, if there is one. Its entries are not merged, so
+    /// the stamp of the file last merged is kept, and the next reload or commit merges them.
+    fn verify_file(&self) -> Result<()> {
+        let stamp = self.integrity()?.stamp.clone();
+        self.read_file()?;
+        self.integrity_mut()?.stamp = stamp;
+        Ok(())
+    }
+
+    /// Reads the store file, if there is one, replays its write-ahead log, checks its integrity
+    /// and mirrors its integrity block, so that a locked handle writes back what it read. The
+    /// integrity lock is held while reading, so that no handle of this store commits in between.
+    fn read_file(&self) -> Result<Option<Self>> {
+        let mut ours = self.integrity_mut()?;
+        let lock = FileLock::shared(&self.path, self.lock_mode, self.read_only)?;
+        let other = self.read_file_locked(&mut ours);
+        drop(lock);
+        other
+    }
+
+    /// Like `read_file`, with the integrity block and a lock on the store file already held by
+    /// the caller.
+    fn read_file_locked(&self, ours: &mut Integrity) -> Result<Option<Self>> {
+        // stamped first, so that a change made while reading is read again next time
+        let stamp = self.stamp();
+        let other: Self = match Self::load(&self.path).ok() {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let log = wal::read(&self.path)?;
+        let checkpoint = other.integrity()?.generation;
+        let has_log = self.verify(&other, log, ours)?;
+        let theirs = other.integrity()?;
+        ours.id = theirs.id.clone();
+        ours.generation = theirs.generation;
+        ours.mac = theirs.mac.clone();
+        ours.checkpoint = checkpoint;
+        ours.has_log = has_log;
+        ours.stamp = Some(stamp);
+        drop(theirs);
+        Ok(Some(other))
+    }
+
+    /// Stamps of the store file and its write-ahead log, as they are now.
+    fn stamp(&self) -> (Option<FileStamp>, Option<FileStamp>) {
+        (
+            FileStamp::of(&self.path),
+            FileStamp::of(&wal::log_path(&self.path)),
+        )
+    }
+
+    /// Checks the MAC of `other`, as read from the store file, with the data key of this handle,
+    /// replays `log` onto it, then checks the generation it ends up at. Nothing can be checked
+    /// while the handle is locked or unencrypted. Returns whether records of the log were
+    /// replayed.
+    fn verify(&self, other: &Self, log: Option<wal::Log>, ours: &mut Integrity) -> Result<bool> {
+        let data_key = match self.keyring()?.pwd() {
+            Ok(Some(key)) => key.clone(),
+            _ => return other.replay(log, None),
+        };
+
+        let storage_map = other.storage.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let mut locked = Vec::new();
+        for (namespace, storage) in storage_map.iter() {
+            let data = storage.read().map_err(|_| KVError {
+                error: ErrorType::PoisonError,
+                msg: None,
+            })?;
+            locked.push((namespace, data));
+        }
+        let mut namespaces: Vec<(&String, &KV)> =
+            locked.iter().map(|(ns, data)| (*ns, &**data)).collect();
+        namespaces.sort_by(|a, b| a.0.cmp(b.0));
+
+        let keyring = other.keyring()?;
+        let theirs = other.integrity()?;
+        let digest = digest(
+            &other.version,
+            (&theirs.id, theirs.generation),
+            &keyring,
+            &namespaces,
+        )?;
+
+        // stores created by 0.4.0 are authenticated from their first commit
+        let required = keyring.slots.iter().any(|slot| !slot.is_legacy());
+        theirs.verify(&digest, &data_key, &self.path, ours.seen, required)?;
+        drop(theirs);
+        drop(keyring);
+        drop(locked);
+        drop(storage_map);
+
+        let has_log = other.replay(log, Some(&data_key))?;
+        let theirs = other.integrity()?;
+        if theirs.mac.is_some() {
+            theirs.verify_generation(&self.path, ours.seen, self.read_only)?;
+            ours.seen = ours.seen.max(theirs.generation);
+        }
+        Ok(has_log)
+    }
+
+    /// Applies the records of `log` onto the store file read into this instance, if the log
+    /// extends it. With `data_key`, each record must be chained to the one before. Returns
+    /// whether any record was replayed.
+    fn replay(&self, log: Option<wal::Log>, data_key: Option<&SecStr>) -> Result<bool> {
+        let log = match log {
+            Some(log) => log,
+            None => return Ok(false),
+        };
+        let mut integrity = self.integrity_mut()?;
+        if log.header.id != integrity.id || log.header.generation != integrity.generation {
+            // a newer log than the store file means the file was rolled back
+            if data_key.is_some()
+                && log.header.id == integrity.id
+                && log.header.generation > integrity.generation
+            {
+                return Err(integrity::integrity_error(
+                    "store was rolled back to an older copy",
+                ));
+            }
+            // otherwise it was left behind by a checkpoint that holds its records
+            return Ok(false);
+        }
+        if data_key.is_some() && log.header.mac != integrity.mac {
+            return Err(integrity::integrity_error("log does not extend the store"));
+        }
+
+        let mut storage_map = self.storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        for record in log.records.iter() {
+            match data_key {
+                Some(key) => {
+                    let authenticated =
+                        LogRecord::authenticated(&integrity.id, record.generation, &record.entries);
+                    integrity.verify_chain(record.generation, &authenticated, &record.mac, key)?
+                }
+                None => {
+                    integrity.generation = record.generation;
+                    integrity.mac = record.mac.clone();
+                }
+            }
+            for entry in record.entries.iter() {
+                let storage = storage_map
+                    .entry(entry.namespace.clone())
+                    .or_insert_with(|| Arc::new(RwLock::new(KV::new())));
+                let mut data = storage.write().map_err(|_| KVError {
+                    error: ErrorType::PoisonError,
+                    msg: None,
+                })?;
+                let _ = data.remove(&entry.key);
+                if let Some(ref value) = entry.value {
+                    data.insert(entry.key.clone(), value.clone());
+                }
+            }
+        }
+        Ok(!log.records.is_empty())
+    }
+
+    /// Returns any one value held by the store.
+    fn any_value(&self) -> Result<Option<SealedValue>> {
+        let storage_map = self.storage.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        for storage in storage_map.values() {
+            let data = storage.read().map_err(|_| KVError {
+                error: ErrorType::PoisonError,
+                msg: None,
+            })?;
+            if let Some(value) = data.values().next() {
+                return Ok(Some(value.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn safe_storage(&self, namespace: impl AsRef<str>) -> Result<()> {
+        self.reload()?;
+        let namespace = namespace.as_ref();
+        let mut storage_map = self.storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        if !storage_map.contains_key(namespace) {
+            let storage = Arc::new(RwLock::new(KV::new()));
+            storage_map.insert(namespace.to_string(), storage);
+        }
+        Ok(())
+    }
+
+    /// Arbitrary read-lock that encapsulates a read-only closure. Multiple concurrent readers
+    /// can hold a lock and parse out data. In stores that blind names, `namespace` and the keys
+    /// seen by the closure are blinded.
+    pub fn lock_read<C, R>(&self, namespace: impl AsRef<str>, callback: C) -> Result<R>
+    where
+        C: Fn(&KV) -> R,
+    {
+        // reloads the store, if another handle changed it
+        let namespace = namespace.as_ref();
+        self.safe_storage(namespace)?;
+        let storage_map = self.storage.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let storage = storage_map.get(namespace).unwrap();
+        let data = storage.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        Ok(callback(&data))
+    }
+
+    /// Arbitrary write-lock that encapsulates a write-only closure Single writer can hold a
+    /// lock and mutate data, blocking any other readers/writers before the lock is released.
+    /// In stores that blind names, `namespace` and the keys seen by the closure are blinded.
+    pub fn lock_write<C, R>(&self, namespace: impl AsRef<str>, mut callback: C) -> Result<R>
+    where
+        C: FnMut(&mut KV) -> R,
+    {
+        self.ensure_writable()?;
+        self.keyring()?.ensure_unlocked()?;
+        // reloads the store, if another handle changed it
+        let namespace = namespace.as_ref();
+        self.safe_storage(namespace)?;
+        let storage_map = self.storage.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let storage = storage_map.get(namespace).unwrap();
+        let mut data = storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        Ok(callback(&mut data))
+    }
+
+    /// Delete namespace
+    pub fn delete_namespace(&self, namespace: impl AsRef<str>) -> Result<()> {
+        self.ensure_writable()?;
+        self.keyring()?.ensure_unlocked()?;
+        let namespace = self.storage_namespace(namespace)?;
+        self.reload()?;
+        let mut storage_map = self.storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        if let Some(storage) = storage_map.remove(&namespace) {
+            let data = storage.read().map_err(|_| KVError {
+                error: ErrorType::PoisonError,
+                msg: None,
+            })?;
+            for key in data.keys() {
+                self.mark_dirty(&namespace, key, &data)?;
+            }
+        }
+        let _ = self.keyring_mut()?.namespaces.remove(&namespace);
+        if self.is_auto_commit {
+            drop(storage_map);
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Decrypts every value with the data key `old` and seals it again under a fresh random
+    /// data key, then replaces every key slot with a single one for `credential`. Values of
+    /// namespaces with a key of their own, and of drop boxes, are left as they are. Stores on
+    /// the legacy cipher are moved to the default one. Every lock is held until the switch is
+    /// done, so no handle can read or write in between, and nothing is changed unless every
+    /// value could be decrypted.
+    pub(crate) fn rekey(
+        &self,
+        old: &Option<SecStr>,
+        credential: &Credential,
+        kdf: Kdf,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        self.reload()?;
+        let new: SecStr = SecVec::new(backend::gen_key());
+        let storage_map = self.storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+
+        let keyring = self.keyring()?;
+        let old_cipher = keyring.cipher;
+        let new_cipher = match old_cipher.is_legacy() {
+            true => Cipher::default(),
+            false => old_cipher,
+        };
+        let new_pwd = Some(new.clone());
+        let mut locked = Vec::new();
+        for (namespace, storage) in storage_map.iter() {
+            let data = storage.write().map_err(|_| KVError {
+                error: ErrorType::PoisonError,
+                msg: None,
+            })?;
+            let mut resealed = KV::new();
+            for (key, sealed) in data.iter() {
+                // values of namespaces with a key of their own, or of a drop box, are left as
+                // they are
+                if keyring.namespace_keyring(namespace, key).is_some()
+                    || keyring.drop_box(key).is_some()
+                {
+                    resealed.insert(key.clone(), sealed.clone());
+                    continue;
+                }
+                let ad = sealed
+                    .encoding
+                    .bind(helpers::associated_data(namespace, key));
+                let value: Vec<u8> = helpers::open_value(sealed, old, old_cipher, &ad)?;
+                let mut value = helpers::seal_value(&value, &new_pwd, new_cipher, &ad)?;
+                // names are sealed under the names key, which is kept across re-keying
+                value.name = sealed.name.clone();
+                value.encoding = sealed.encoding;
+                value.version = sealed.version;
+                resealed.insert(key.clone(), value);
+            }
+            locked.push((data, resealed));
+        }
+        drop(keyring);
+
+        let mut keyring = self.keyring_mut()?;
+        keyring.set_key(new.clone(), new_cipher)?;
+        keyring.reset_slots(credential, kdf.clone(), &new)?;
+        keyring.kdf = kdf;
+        for (mut data, resealed) in locked {
+            *data = resealed;
+        }
+        Ok(())
+    }
+
+    ///////////////////
+    // I/O Operations
+    ///////////////////
+
+    /// Writes the IndexMap to persistent storage after encrypting with secure crypto construction.
+    /// An unlocked encrypted store is authenticated under a new generation.
+    ///
+    /// Entries committed by other handles since this one last read the store file are merged
+    /// in first, as a reload would, so that they are not replaced.
+    pub fn commit(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let mut storage_map = self.storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let conflicts = self.commit_storage(&mut storage_map)?;
+        drop(storage_map);
+        self.report_conflicts(&conflicts);
+        Ok(())
+    }
+
+    /// Like `commit`, with `storage_map` already locked by the caller. Returns the conflicts
+    /// settled while merging the store file, to be reported once the caller released it.
+    fn commit_storage(&self, storage_map: &mut HashMap<String, Storage>) -> Result<Vec<Conflict>> {
+        self.ensure_writable()?;
+        let mut integrity = self.integrity_mut()?;
+        // taken before signing, so that a commit that can't lock the file changes nothing
+        let lock = FileLock::exclusive(&self.path, self.lock_mode)?;
+        let mut conflicts = Vec::new();
+        if integrity.stamp != Some(self.stamp()) {
+            if let Some(other) = self.read_file_locked(&mut integrity)? {
+                conflicts = self.merge_file(storage_map, &other)?;
+            }
+        }
+
+        let mut locked = Vec::new();
+        for (namespace, storage) in storage_map.iter() {
+            let data = storage.read().map_err(|_| KVError {
+                error: ErrorType::PoisonError,
+                msg: None,
+            })?;
+            locked.push((namespace, data));
+        }
+        let mut namespaces: Vec<(&String, &KV)> =
+            locked.iter().map(|(ns, data)| (*ns, &**data)).collect();
+        namespaces.sort_by(|a, b| a.0.cmp(b.0));
+
+        let keyring = self.keyring()?;
+        let data_key = match keyring.pwd() {
+            Ok(key) => key.clone(),
+            Err(_) => None,
+        };
+        let signed = match data_key {
+            Some(key) => {
+                let generation = integrity.generation.max(integrity.seen) + 1;
+                let digest = digest(
+                    &self.version,
+                    (&integrity.id, generation),
+                    &keyring,
+                    &namespaces,
+                )?;
+                integrity.sign(generation, &digest, &key);
+                Some(generation)
+            }
+
+            // the MAC a locked handle read is chained to the log, and can't be checked against
+            // the store it replayed, which it can't have changed anyway
+            None if keyring.is_encrypted() && integrity.has_log => return Ok(conflicts),
+
+            // a locked handle can't have changed the store, so the MAC it read still holds
+            None if keyring.is_encrypted() => None,
+
+            None => {
+                integrity.generation += 1;
+                integrity.mac = None;
+                None
+            }
+        };
+
+        let view = CommitView {
+            version: &self.version,
+            storage: namespaces.into_iter().collect(),
+            keyring: &keyring,
+            integrity: &integrity,
+            is_auto_commit: self.is_auto_commit,
+        };
+        let header = Header::new(&self.version, &keyring);
+        helpers::persist(&self.path, &format::join(&header, &view)?)?;
+        // the store now holds the records of the log, and other handles may still append to it
+        if self.write_ahead_log || wal::log_path(&self.path).is_file() {
+            let header = LogHeader {
+                id: integrity.id.clone(),
+                generation: integrity.generation,
+                mac: integrity.mac.clone(),
+            };
+            wal::create(&self.path, &header)?;
+        }
+        integrity.checkpoint = integrity.generation;
+        integrity.has_log = false;
+        integrity.stamp = Some(self.stamp());
+        self.dirty_mut()?.clear();
+        drop(lock);
+        if let Some(generation) = signed {
+            integrity::cache_generation(&self.path, &integrity.id, generation);
+        }
+        Ok(conflicts)
+    }
+
+    /// Persists `entries`, just changed, if auto-commit is set: by appending them to the
+    /// write-ahead log if it is enabled, or else by committing the whole store.
+    pub(crate) fn auto_commit(&self, entries: Vec<LogEntry>) -> Result<()> {
+        if !self.is_auto_commit {
+            return Ok(());
+        }
+        match self.write_ahead_log {
+            true => self.append(entries, None).map(|_| ()),
+            false => self.commit(),
+        }
+    }
+
+    /// Like `auto_commit`, with `storage_map` already locked by the caller, e.g. a transaction
+    /// that keeps its writes from being seen until they are persisted. Returns the conflicts
+    /// settled by the commit, to be reported once the caller released `storage_map`.
+    pub(crate) fn auto_commit_locked(
+        &self,
+        entries: Vec<LogEntry>,
+        storage_map: &mut HashMap<String, Storage>,
+    ) -> Result<Vec<Conflict>> {
+        if !self.is_auto_commit {
+            return Ok(Vec::new());
+        }
+        match self.write_ahead_log {
+            true => self.append(entries, Some(storage_map)),
+            false => self.commit_storage(storage_map),
+        }
+    }
+
+    /// Appends `entries` to the write-ahead log as a record of the next generation. The whole
+    /// store is committed instead if the log does not extend the store this handle last read or
+    /// wrote, e.g. as no log was started yet, and once the log outgrows the store.
+    ///
+    /// `storage_map` is given if the caller already holds it locked, and the conflicts settled
+    /// by committing it are returned for the caller to report.
+    fn append(
+        &self,
+        entries: Vec<LogEntry>,
+        storage_map: Option<&mut HashMap<String, Storage>>,
+    ) -> Result<Vec<Conflict>> {
+        self.ensure_writable()?;
+        let mut integrity = self.integrity_mut()?;
+        let keyring = self.keyring()?;
+        let lock = FileLock::exclusive(&self.path, self.lock_mode)?;
+        let is_current = integrity.stamp == Some(self.stamp());
+        let log = match wal::read(&self.path)? {
+            Some(log)
+                if log.header.id == integrity.id
+                    && log.header.generation == integrity.checkpoint =>
+            {
+                log
+            }
+            _ => {
+                drop(lock);
+                drop(keyring);
+                drop(integrity);
+                return self.commit_with(storage_map);
+            }
+        };
+
+        // records written by other handles since the last reload are kept
+        let (generation, mac) = log.head();
+        let generation = generation + 1;
+        let record = match keyring.pwd() {
+            Ok(Some(key)) => {
+                integrity.generation = generation - 1;
+                integrity.mac = mac;
+                let authenticated = LogRecord::authenticated(&integrity.id, generation, &entries);
+                integrity.chain(generation, &authenticated, key);
+                LogRecord {
+                    generation,
+                    entries,
+                    mac: integrity.mac.clone(),
+                }
+            }
+            Ok(None) => {
+                integrity.generation = generation;
+                integrity.mac = None;
+                LogRecord {
+                    generation,
+                    entries,
+                    mac: None,
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        let len = wal::append(&self.path, &log, &record)?;
+        integrity.has_log = true;
+        let mut dirty = self.dirty_mut()?;
+        for entry in record.entries.iter() {
+            if let Some(keys) = dirty.get_mut(&entry.namespace) {
+                let _ = keys.remove(&entry.key);
+            }
+        }
+        dirty.retain(|_, keys| !keys.is_empty());
+        drop(dirty);
+        // records appended by other handles are still to be read
+        if is_current {
+            integrity.stamp = Some(self.stamp());
+        }
+        drop(lock);
+        if record.mac.is_some() {
+            integrity::cache_generation(&self.path, &integrity.id, generation);
+        }
+
+        let checkpoint_len = fs::metadata(&self.path)?.len();
+        if len > wal::COMPACTION_THRESHOLD && len > checkpoint_len {
+            drop(keyring);
+            drop(integrity);
+            return self.commit_with(storage_map);
+        }
+        Ok(Vec::new())
+    }
+
+    /// Commits the store, locking its storage map unless the caller already holds it. Returns
+    /// the conflicts left for the caller to report.
+    fn commit_with(
+        &self,
+        storage_map: Option<&mut HashMap<String, Storage>>,
+    ) -> Result<Vec<Conflict>> {
+        match storage_map {
+            Some(storage_map) => self.commit_storage(storage_map),
+            None => self.commit().map(|_| Vec::new()),
+        }
+    }
+
+    /// Clears the underlying data structure for the key-value store, and deletes the database file to remove all traces.
+    pub fn destruct(&self) -> Result<()> {
+        unimplemented!();
+    }
+
+    ///////////////////
+    // Additional
+    ///////////////////
+
+    /// Merge other MicroKV instance, if the store file or its write-ahead log changed since
+    /// this handle last read or wrote them.
+    pub(crate) fn reload(&self) -> Result<()> {
+        if self.integrity()?.stamp == Some(self.stamp()) {
+            return Ok(());
+        }
+        let other: Self = match self.read_file()? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let mut storage_map = self.storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let conflicts = self.merge_file(&mut storage_map, &other)?;
+        drop(storage_map);
+        self.report_conflicts(&conflicts);
+        Ok(())
+    }
+
+    /// Merges `other`, as read from the store file, into `storage_map`, entry by entry. Returns
+    /// the conflicts that were settled.
+    fn merge_file(
+        &self,
+        storage_map: &mut HashMap<String, Storage>,
+        other: &Self,
+    ) -> Result<Vec<Conflict>> {
+        let theirs = other.storage.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let (merged, conflicts) = merge::merge(
+            storage_map,
+            &theirs,
+            &mut *self.dirty_mut()?,
+            self.conflict_policy,
+        )?;
+        *storage_map = merged;
+        Ok(conflicts)
+    }
+
+    /// Reports `conflicts` to the conflict handler. Called once no lock is held, so that the
+    /// handler can use the store.
+    pub(crate) fn report_conflicts(&self, conflicts: &[Conflict]) {
+        if let Some(ref handler) = self.conflict_handler {
+            for conflict in conflicts.iter() {
+                handler(conflict);
+            }
+        }
+    }
+}
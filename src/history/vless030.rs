@@ -4,11 +4,11 @@ use std::sync::{Arc, RwLock};
 use secstr::{SecStr, SecVec};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use sodiumoxide::crypto::secretbox::Nonce;
 
+use crate::cipher::Cipher;
 use crate::errors::{ErrorType, KVError, Result};
 use crate::helpers;
-use crate::types::KV;
+use crate::types::{LegacyKV, Nonce, SealedValue};
 
 /// The MicroKV class version less than 0.3.0
 #[derive(Clone, Serialize, Deserialize)]
@@ -16,7 +16,7 @@ pub struct MicroKVLess030 {
     pub(crate) path: PathBuf,
 
     /// stores the actual key-value store encapsulated with a RwLock
-    pub(crate) storage: Arc<RwLock<KV>>,
+    pub(crate) storage: Arc<RwLock<LegacyKV>>,
 
     /// pseudorandom nonce that can be publicly known
     pub(crate) nonce: Nonce,
@@ -29,26 +29,56 @@ pub struct MicroKVLess030 {
     pub(crate) is_auto_commit: bool,
 }
 
+impl MicroKVLess030 {
+    pub fn create(
+        path: PathBuf,
+        pwd: Option<SecStr>,
+        nonce: Nonce,
+        is_auto_commit: bool,
+        storage: Arc<RwLock<LegacyKV>>,
+    ) -> Self {
+        Self {
+            path,
+            storage,
+            nonce,
+            pwd,
+            is_auto_commit,
+        }
+    }
+}
+
 impl MicroKVLess030 {
     pub fn encode_value<V>(&self, value: &V) -> Result<SecVec<u8>>
     where
         V: Serialize,
     {
-        helpers::encode_value(value, &self.pwd, &self.nonce)
+        helpers::encode_value_with_nonce(value, &self.pwd, &self.nonce).map(|v| v.data)
     }
 
     pub fn decode_value<V>(&self, value: &SecVec<u8>) -> Result<V>
     where
         V: DeserializeOwned + 'static,
     {
-        helpers::decode_value(value, &self.pwd, &self.nonce)
+        let value = SealedValue::new(Some(self.nonce.0.to_vec()), value.clone());
+        helpers::decode_value(&value, &self.pwd)
+    }
+
+    /// Decrypts a value to the bincode encoding of its own type, which the store does not
+    /// record.
+    pub(crate) fn open_value(&self, value: &SecVec<u8>) -> Result<Vec<u8>> {
+        match self.pwd {
+            Some(ref pwd) => {
+                Cipher::XSalsa20Poly1305.open(value.unsecure(), &[], &self.nonce.0, pwd)
+            }
+            None => Ok(value.unsecure().to_vec()),
+        }
     }
 
     /// Arbitrary read-lock that encapsulates a read-only closure. Multiple concurrent readers
     /// can hold a lock and parse out data.
     pub fn lock_read<C, R>(&self, callback: C) -> Result<R>
     where
-        C: Fn(&KV) -> R,
+        C: Fn(&LegacyKV) -> R,
     {
         let data = self.storage.read().map_err(|_| KVError {
             error: ErrorType::PoisonError,
@@ -61,7 +91,7 @@ impl MicroKVLess030 {
     /// lock and mutate data, blocking any other readers/writers before the lock is released.
     pub fn lock_write<C, R>(&self, mut callback: C) -> Result<R>
     where
-        C: FnMut(&KV) -> R,
+        C: FnMut(&LegacyKV) -> R,
     {
         let mut data = self.storage.write().map_err(|_| KVError {
             error: ErrorType::PoisonError,
@@ -0,0 +1,198 @@
+//! Defines the integrity block of a store. Every commit of an encrypted store bumps a generation
+//! counter and authenticates the whole store with an HMAC keyed by the data key, so that entries
+//! or namespaces removed from the file, or an older copy put in its place, are detected.
+//!
+//! The highest generation seen for each store is also cached under the home directory, so that
+//! rolling the file back is detected across processes as well.
+//!
+//! Records of the write-ahead log each take the next generation, and are authenticated with a
+//! MAC chained to the one before, down to the MAC of the store they extend.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::backend;
+use crate::errors::{ErrorType, KVError, Result};
+use crate::helpers::{self, FileStamp};
+use secstr::SecStr;
+use serde::{Deserialize, Serialize};
+
+/// Label the MAC key is derived from the data key with.
+const MAC_KEY_LABEL: &[u8] = b"microkv integrity";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Integrity {
+    /// random identifier of the store, so that a new store at the same path is not taken
+    /// for an older copy of the previous one
+    pub(crate) id: Vec<u8>,
+
+    /// number of commits made to the store
+    pub(crate) generation: u64,
+
+    /// HMAC-SHA-256 over the digest of the store. `None` if the store is unencrypted, or was
+    /// created before 0.4.0 and has not been committed unlocked since.
+    pub(crate) mac: Option<Vec<u8>>,
+
+    /// highest authenticated generation this handle has seen
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) seen: u64,
+
+    /// generation of the store file this handle read or wrote last, below the records of its
+    /// write-ahead log
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) checkpoint: u64,
+
+    /// whether records of the write-ahead log were replayed onto the store file read last
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) has_log: bool,
+
+    /// stamps of the store file and its write-ahead log when this handle last read or wrote
+    /// them, `None` until it has
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) stamp: Option<(Option<FileStamp>, Option<FileStamp>)>,
+}
+
+impl Integrity {
+    pub(crate) fn new() -> Self {
+        Self {
+            id: backend::randombytes(16),
+            generation: 0,
+            mac: None,
+            seen: 0,
+            checkpoint: 0,
+            has_log: false,
+            stamp: None,
+        }
+    }
+
+    /// Authenticates `digest` as generation `generation`.
+    pub(crate) fn sign(&mut self, generation: u64, digest: &[u8], data_key: &SecStr) {
+        self.generation = generation;
+        self.mac = Some(backend::hmac_sha256(&mac_key(data_key), digest).to_vec());
+        self.seen = generation;
+    }
+
+    /// Authenticates `record` of the write-ahead log as generation `generation`, chained to the
+    /// MAC of the store or record before it.
+    pub(crate) fn chain(&mut self, generation: u64, record: &[u8], data_key: &SecStr) {
+        self.generation = generation;
+        self.mac = Some(backend::hmac_sha256(&mac_key(data_key), &self.chained(record)).to_vec());
+        self.seen = generation;
+    }
+
+    /// Checks the MAC of `record` of the write-ahead log, chained to the MAC of the store or
+    /// record before it, and moves on to `generation`, which must be the next one.
+    pub(crate) fn verify_chain(
+        &mut self,
+        generation: u64,
+        record: &[u8],
+        mac: &Option<Vec<u8>>,
+        data_key: &SecStr,
+    ) -> Result<()> {
+        let is_valid = match mac {
+            Some(mac) => {
+                backend::verify_hmac_sha256(mac, &mac_key(data_key), &self.chained(record))
+            }
+            None => false,
+        };
+        if !is_valid || generation != self.generation + 1 {
+            return Err(integrity_error("log was modified outside of microkv"));
+        }
+        self.generation = generation;
+        self.mac = mac.clone();
+        Ok(())
+    }
+
+    /// `record` prefixed with the MAC it is chained to.
+    fn chained(&self, record: &[u8]) -> Vec<u8> {
+        let mut msg = self.mac.clone().unwrap_or_default();
+        msg.extend_from_slice(record);
+        msg
+    }
+
+    /// Checks the MAC over `digest`. `required` is set if the store must carry a MAC, which it
+    /// also must once a generation was seen by this handle or cached for the store at `path`.
+    pub(crate) fn verify(
+        &self,
+        digest: &[u8],
+        data_key: &SecStr,
+        path: &Path,
+        seen: u64,
+        required: bool,
+    ) -> Result<()> {
+        let latest = seen.max(cached_generation(path, &self.id));
+        let mac = match self.mac {
+            Some(ref mac) => mac,
+            None if !required && latest == 0 => return Ok(()),
+            None => return Err(integrity_error("store is not authenticated")),
+        };
+        if !backend::verify_hmac_sha256(mac, &mac_key(data_key), digest) {
+            return Err(integrity_error("store was modified outside of microkv"));
+        }
+        Ok(())
+    }
+
+    /// Checks that the store, once its write-ahead log is replayed, did not go back to an older
+    /// generation than one seen before by this handle or cached for the store at `path`. The
+    /// cache is only written when the generation advances, and never by a `read_only` handle.
+    pub(crate) fn verify_generation(&self, path: &Path, seen: u64, read_only: bool) -> Result<()> {
+        let cached = cached_generation(path, &self.id);
+        if self.generation < seen.max(cached) {
+            return Err(integrity_error("store was rolled back to an older copy"));
+        }
+        if self.generation > cached && !read_only {
+            cache_generation(path, &self.id, self.generation);
+        }
+        Ok(())
+    }
+}
+
+/// Derives the MAC key from the data key, so that it is never used for two purposes.
+fn mac_key(data_key: &SecStr) -> [u8; 32] {
+    backend::hmac_sha256(data_key.unsecure(), MAC_KEY_LABEL)
+}
+
+pub(crate) fn integrity_error(msg: &str) -> KVError {
+    KVError {
+        error: ErrorType::IntegrityError,
+        msg: Some(msg.to_string()),
+    }
+}
+
+/// File the highest generation of the store `id` at `path` is cached in.
+fn cache_path(path: &Path, id: &[u8]) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut name = path.to_string_lossy().as_bytes().to_vec();
+    name.extend_from_slice(id);
+    let name = backend::sha256(&name)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let mut cache = dirs::home_dir()?;
+    cache.push(helpers::DEFAULT_WORKSPACE_PATH);
+    cache.push("generations");
+    cache.push(name);
+    Some(cache)
+}
+
+/// Highest generation cached for the store `id` at `path`, 0 if none.
+pub(crate) fn cached_generation(path: &Path, id: &[u8]) -> u64 {
+    cache_path(path, id)
+        .and_then(|cache| fs::read_to_string(cache).ok())
+        .and_then(|generation| generation.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Caches `generation` for the store `id` at `path`, unless a higher one is cached. The cache
+/// is best-effort: a store can still be used if it can't be written.
+pub(crate) fn cache_generation(path: &Path, id: &[u8], generation: u64) {
+    if generation <= cached_generation(path, id) {
+        return;
+    }
+    if let Some(cache) = cache_path(path, id) {
+        if let Some(dir) = cache.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = fs::write(cache, generation.to_string());
+    }
+}
@@ -0,0 +1,87 @@
+//! Defines the key derivation functions that turn a cleartext password into the 32-byte key
+//! used to seal values. The chosen function and its parameters are persisted with the store,
+//! so that the same key can be derived again when the store is reopened.
+
+use crate::backend;
+use crate::errors::{ErrorType, KVError, Result};
+use crate::types::Salt;
+use secstr::{SecStr, SecVec};
+use serde::{Deserialize, Serialize};
+
+/// Password-based key derivation function recorded in the store header.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Kdf {
+    /// A single unsalted SHA-256 of the password. Only used by stores created before 0.4.0,
+    /// and kept so that those can still be opened and upgraded.
+    Sha256,
+
+    /// Memory-hard Argon2id with a per-store random salt.
+    Argon2id {
+        salt: Salt,
+        opslimit: u64,
+        memlimit: u64,
+    },
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Self::argon2id()
+    }
+}
+
+impl Kdf {
+    /// Argon2id with a fresh salt and the interactive cost parameters recommended by libsodium.
+    pub fn argon2id() -> Self {
+        Self::argon2id_with_limits(
+            backend::ARGON2ID_OPSLIMIT_INTERACTIVE,
+            backend::ARGON2ID_MEMLIMIT_INTERACTIVE,
+        )
+    }
+
+    /// Argon2id with a fresh salt, `opslimit` passes over memory and `memlimit` bytes of memory.
+    /// Raising either makes brute-forcing the password from a stolen store more expensive.
+    pub fn argon2id_with_limits(opslimit: u64, memlimit: u64) -> Self {
+        Kdf::Argon2id {
+            salt: Salt::generate(),
+            opslimit,
+            memlimit,
+        }
+    }
+
+    /// The same derivation with a fresh salt, for use with a new password. The legacy
+    /// SHA-256 derivation is replaced with Argon2id.
+    pub fn renew(&self) -> Self {
+        match self {
+            Kdf::Sha256 => Self::argon2id(),
+            Kdf::Argon2id {
+                opslimit, memlimit, ..
+            } => Self::argon2id_with_limits(*opslimit, *memlimit),
+        }
+    }
+
+    /// Whether this is the legacy derivation that should be upgraded with `upgrade_kdf`.
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Kdf::Sha256)
+    }
+
+    /// Derives a key from a cleartext password.
+    pub fn derive_key(&self, pwd: &[u8]) -> Result<SecStr> {
+        match self {
+            Kdf::Sha256 => Ok(SecVec::new(backend::sha256(pwd).to_vec())),
+            Kdf::Argon2id {
+                salt,
+                opslimit,
+                memlimit,
+            } => {
+                let mut key: SecStr = SecVec::new(vec![0; backend::KEY_BYTES]);
+                backend::argon2id(key.unsecure_mut(), pwd, &salt.0, *opslimit, *memlimit).map_err(
+                    |_| KVError {
+                        error: ErrorType::CryptoError,
+                        msg: Some("cannot derive key from password with argon2id".to_string()),
+                    },
+                )?;
+                Ok(key)
+            }
+        }
+    }
+}
@@ -0,0 +1,612 @@
+//! Defines the key material of a store. Values are sealed with a random data key, and that
+//! data key is kept in one or more key slots, each sealing a copy of it under a key derived
+//! from its own password, keyfile or hash, or handing it to a `KeyWrapper` such as a KMS. Any
+//! one of them unlocks the store, and changing a password, or adding and removing slots, only
+//! wraps the 32-byte data key again without touching the values.
+//!
+//! A `Keyring` is shared by every handle cloned from the same store, so that changing the
+//! password through one handle is seen by all others. When several are needed, locks are taken
+//! in the order storage, namespaces, integrity, then keyring.
+//!
+//! A namespace can also be given a key of its own, see `NamespaceMicroKV::with_pwd_clear`. Its
+//! values are then sealed under the data key of a separate `Keyring`, with slots of its own, so
+//! that neither the key of the store nor that of another namespace opens them.
+//!
+//! A store can also be a drop box, see `crate::dropbox`, in which case every value is sealed to
+//! its public key rather than under a data key.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use secstr::{SecStr, SecVec};
+use serde::{Deserialize, Serialize};
+
+use crate::backend;
+use crate::cipher::Cipher;
+use crate::encoding::{Compression, Padding};
+use crate::errors::{ErrorType, KVError, Result};
+use crate::helpers;
+use crate::history::NAMESPACE_ENTRY;
+use crate::kdf::Kdf;
+use crate::provider::KeyWrapper;
+use crate::types::SealedValue;
+
+/// Known plaintext sealed into the key check block of an encrypted store.
+const KEY_CHECK: &str = "microkv key check";
+
+/// Associated data of the blocks sealed in the keyring, so that none can be swapped for another.
+const KEY_CHECK_AD: &[u8] = b"key check";
+const NAMES_KEY_AD: &[u8] = b"names key";
+const KEY_SLOT_AD: &[u8] = b"key slot";
+
+/// Name of the slot created when a store is first given a password.
+pub const DEFAULT_SLOT: &str = "default";
+
+/// A secret that unlocks a key slot.
+#[derive(Clone)]
+pub enum Credential {
+    /// a cleartext password
+    Password(SecStr),
+    /// the contents of a keyfile
+    Keyfile(SecVec<u8>),
+    /// a 32-byte hash, used as a key as-is
+    Hash(SecStr),
+    /// an external service that wraps the data key, e.g. a KMS
+    Wrapper(Arc<dyn KeyWrapper>),
+}
+
+impl Credential {
+    pub fn password<S: AsRef<str>>(pwd: S) -> Self {
+        Credential::Password(SecVec::new(pwd.as_ref().as_bytes().to_vec()))
+    }
+
+    /// Reads the contents of a keyfile.
+    pub fn keyfile<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut contents: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut contents)?;
+        Ok(Credential::Keyfile(SecVec::new(contents)))
+    }
+
+    pub fn hash(hash: [u8; 32]) -> Self {
+        Credential::Hash(SecVec::new(hash.to_vec()))
+    }
+
+    pub fn wrapper<W: KeyWrapper + 'static>(wrapper: W) -> Self {
+        Credential::Wrapper(Arc::new(wrapper))
+    }
+
+    /// The kind of slot this credential unlocks, with `kdf` used to stretch passwords and keyfiles.
+    fn slot_kind(&self, kdf: Kdf) -> SlotKind {
+        match self {
+            Credential::Password(_) => SlotKind::Password(kdf),
+            Credential::Keyfile(_) => SlotKind::Keyfile(kdf),
+            Credential::Hash(_) => SlotKind::Hash,
+            Credential::Wrapper(wrapper) => SlotKind::Wrapper { id: wrapper.id() },
+        }
+    }
+}
+
+/// What unlocks a key slot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SlotKind {
+    /// a cleartext password, stretched with the KDF
+    Password(Kdf),
+    /// the contents of a keyfile, stretched with the KDF
+    Keyfile(Kdf),
+    /// a 32-byte hash
+    Hash,
+    /// a `KeyWrapper`, identified by the id of its wrapping key
+    Wrapper { id: String },
+}
+
+impl SlotKind {
+    /// Derives the key of this slot, `None` if the credential is for another kind of slot, or
+    /// wraps the data key itself.
+    fn derive_key(&self, credential: &Credential) -> Result<Option<SecStr>> {
+        let key = match (self, credential) {
+            (SlotKind::Password(kdf), Credential::Password(pwd)) => {
+                kdf.derive_key(pwd.unsecure())?
+            }
+            (SlotKind::Keyfile(kdf), Credential::Keyfile(contents)) => {
+                kdf.derive_key(contents.unsecure())?
+            }
+            (SlotKind::Hash, Credential::Hash(hash)) => hash.clone(),
+            _ => return Ok(None),
+        };
+        Ok(Some(key))
+    }
+}
+
+/// A copy of the data key, sealed under a key derived from one credential.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub name: String,
+    pub kind: SlotKind,
+
+    /// data key sealed under the key of this slot, or as returned by its `KeyWrapper`. `None`
+    /// for the slot given to stores created before 0.4.0, where the key derived from the
+    /// password is the data key itself.
+    wrapped: Option<SealedValue>,
+}
+
+impl KeySlot {
+    /// Seals `data_key` with `cipher` under the key derived from `credential`.
+    fn seal(
+        name: &str,
+        credential: &Credential,
+        kdf: Kdf,
+        data_key: &SecStr,
+        cipher: Cipher,
+    ) -> Result<Self> {
+        let kind = credential.slot_kind(kdf);
+        let wrapped = match credential {
+            Credential::Wrapper(wrapper) => {
+                let wrapped = wrapper.wrap(data_key.unsecure())?;
+                SealedValue::new(None, SecVec::new(wrapped))
+            }
+            _ => {
+                let key = kind.derive_key(credential)?;
+                helpers::seal_value(&data_key.unsecure(), &key, cipher, KEY_SLOT_AD)?
+            }
+        };
+        Ok(Self {
+            name: name.to_string(),
+            kind,
+            wrapped: Some(wrapped),
+        })
+    }
+
+    /// Slot of a store created before 0.4.0 that was migrated without its password, so that
+    /// the store is still known to be encrypted. The credential it takes is only checked once
+    /// given, against a value of the store.
+    pub(crate) fn legacy(kind: SlotKind) -> Self {
+        Self {
+            name: DEFAULT_SLOT.to_string(),
+            kind,
+            wrapped: None,
+        }
+    }
+
+    /// Whether the data key is derived straight from the credential, as in stores created
+    /// before 0.4.0. Such a key can only be changed by sealing every value again.
+    pub fn is_legacy(&self) -> bool {
+        self.wrapped.is_none()
+    }
+
+    /// Opens the data key with `credential`, `None` if it is not the credential of this slot.
+    fn open(&self, credential: &Credential, cipher: Cipher) -> Result<Option<SecStr>> {
+        if let (SlotKind::Wrapper { id }, Credential::Wrapper(wrapper)) = (&self.kind, credential) {
+            return match self.wrapped {
+                // the wrapper holds the key of this slot, so it failing is not a wrong key
+                Some(ref wrapped) if *id == wrapper.id() => {
+                    wrapper.unwrap(wrapped.data.unsecure()).map(Some)
+                }
+                _ => Ok(None),
+            };
+        }
+        let key = match self.kind.derive_key(credential)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        match self.wrapped {
+            Some(ref wrapped) => {
+                let key = Some(key);
+                Ok(
+                    helpers::open_value::<Vec<u8>>(wrapped, &key, cipher, KEY_SLOT_AD)
+                        .ok()
+                        .map(SecVec::new),
+                )
+            }
+            None => Ok(Some(key)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Keyring {
+    /// key derivation function and parameters for the first password of the store, and for
+    /// stores created before 0.4.0 that have not been unlocked since
+    pub(crate) kdf: Kdf,
+
+    /// cipher every value and key slot of the store is sealed with
+    pub(crate) cipher: Cipher,
+
+    /// whether namespace and key names are blinded, see `MicroKV::with_blind_names`
+    pub(crate) blind_names: bool,
+
+    /// size buckets new values are padded to, see `MicroKV::with_padding`
+    pub(crate) padding: Padding,
+
+    /// compression applied to new values, see `MicroKV::with_compression`
+    pub(crate) compression: Compression,
+
+    /// random key names are blinded and sealed with, itself sealed under the data key so that
+    /// it survives re-keying. `None` unless names are blinded.
+    pub(crate) names_key: Option<SealedValue>,
+
+    /// every slot holding a copy of the data key
+    pub(crate) slots: Vec<KeySlot>,
+
+    /// known plaintext sealed under the data key, used to tell a wrong password apart from
+    /// a correct one as soon as the store is unlocked. `None` if the store is unencrypted.
+    pub(crate) key_check: Option<SealedValue>,
+
+    /// keyrings of the namespaces that have a key of their own, by the name they are stored
+    /// under. Always empty in those keyrings themselves.
+    pub(crate) namespaces: BTreeMap<String, Keyring>,
+
+    /// public key every value is sealed to, if the store is a drop box
+    pub(crate) public_key: Option<Vec<u8>>,
+
+    /// memory-guarded secret key of a drop box, needed to read values back
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) secret_key: Option<SecStr>,
+
+    /// memory-guarded data key
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) pwd: Option<SecStr>,
+
+    /// set if the last password given did not unlock the store
+    #[serde(skip_serializing, skip_deserializing)]
+    pub(crate) is_locked: bool,
+}
+
+impl Keyring {
+    pub fn new(kdf: Kdf, pwd: Option<SecStr>) -> Self {
+        Self {
+            kdf,
+            cipher: Cipher::default(),
+            blind_names: false,
+            padding: Padding::default(),
+            compression: Compression::default(),
+            names_key: None,
+            slots: Vec::new(),
+            key_check: None,
+            namespaces: BTreeMap::new(),
+            public_key: None,
+            secret_key: None,
+            pwd,
+            is_locked: false,
+        }
+    }
+
+    /// Whether values are, or are about to be, sealed with a key.
+    pub(crate) fn is_encrypted(&self) -> bool {
+        !self.slots.is_empty() || self.key_check.is_some()
+    }
+
+    /// Whether a credential can only be checked against a value of the store, as for stores
+    /// created before 0.4.0 that have not been unlocked since, or stores without key slots.
+    pub(crate) fn needs_fallback(&self) -> bool {
+        self.key_check.is_none() && self.slots.iter().all(KeySlot::is_legacy)
+    }
+
+    /// Refuses access to values while the store is encrypted but not unlocked with the right key.
+    pub(crate) fn ensure_unlocked(&self) -> Result<()> {
+        if self.is_locked {
+            return Err(KVError {
+                error: ErrorType::InvalidPassword,
+                msg: Some("store was unlocked with an invalid password".to_string()),
+            });
+        }
+        if self.is_encrypted() && self.pwd.is_none() {
+            return Err(KVError {
+                error: ErrorType::InvalidPassword,
+                msg: Some("store is encrypted, but no password was given".to_string()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the key values are sealed with, `None` for an unencrypted store.
+    pub(crate) fn pwd(&self) -> Result<&Option<SecStr>> {
+        self.ensure_unlocked()?;
+        Ok(&self.pwd)
+    }
+
+    /// Unlocks the store with `credential`.
+    ///
+    /// A store without key slots is given a random data key and a first slot for `credential`
+    /// if it holds no values yet. Otherwise it was created before 0.4.0, and the key derived
+    /// from `credential` is checked against `fallback`, any value from the store, and kept
+    /// in a legacy slot, as it is for a store with a legacy slot and no key check yet.
+    /// Returns the position of the slot `credential` opened.
+    pub(crate) fn unlock(
+        &mut self,
+        credential: &Credential,
+        fallback: Option<SealedValue>,
+    ) -> Result<usize> {
+        if !self.needs_fallback() {
+            let (index, data_key) = self.open_slot(credential)?;
+            self.pwd = Some(data_key);
+            self.is_locked = false;
+            return Ok(index);
+        }
+
+        let slot = match fallback {
+            None => {
+                let data_key: SecStr = SecVec::new(backend::gen_key());
+                KeySlot::seal(
+                    DEFAULT_SLOT,
+                    credential,
+                    self.kdf.clone(),
+                    &data_key,
+                    self.cipher,
+                )?
+            }
+            Some(_) => KeySlot {
+                name: DEFAULT_SLOT.to_string(),
+                kind: credential.slot_kind(self.kdf.clone()),
+                wrapped: None,
+            },
+        };
+        let data_key = match slot.open(credential, self.cipher)? {
+            Some(key) => key,
+            None => return Err(self.invalid_password()),
+        };
+        if let Some(check) = fallback {
+            if helpers::decode_value::<String>(&check, &Some(data_key.clone())).is_err() {
+                return Err(self.invalid_password());
+            }
+        }
+        self.slots = vec![slot];
+        self.set_key(data_key, self.cipher)?;
+        Ok(0)
+    }
+
+    /// Finds the slot `credential` opens, and returns its position and the data key.
+    pub(crate) fn open_slot(&mut self, credential: &Credential) -> Result<(usize, SecStr)> {
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(data_key) = slot.open(credential, self.cipher)? {
+                let is_valid = match self.key_check {
+                    Some(ref check) => {
+                        let key = Some(data_key.clone());
+                        helpers::open_value::<String>(check, &key, self.cipher, KEY_CHECK_AD)
+                            .is_ok()
+                    }
+                    None => true,
+                };
+                if is_valid {
+                    return Ok((index, data_key));
+                }
+            }
+        }
+        Err(self.invalid_password())
+    }
+
+    fn invalid_password(&mut self) -> KVError {
+        self.lock();
+        KVError {
+            error: ErrorType::InvalidPassword,
+            msg: Some("password does not unlock the store".to_string()),
+        }
+    }
+
+    /// Forgets the key, refusing access to values until unlocked again.
+    pub(crate) fn lock(&mut self) {
+        if let Some(ref mut pwd) = self.pwd {
+            pwd.zero_out()
+        }
+        self.pwd = None;
+        self.is_locked = true;
+    }
+
+    /// Replaces the data key and cipher, sealing a new key check block under them. Values must
+    /// already have been resealed, and slots must be sealed again afterwards.
+    pub(crate) fn set_key(&mut self, key: SecStr, cipher: Cipher) -> Result<()> {
+        let names_key: Option<SecStr> = match (&self.names_key, &self.pwd) {
+            (Some(sealed), Some(_)) => Some(SecVec::new(helpers::open_value(
+                sealed,
+                &self.pwd,
+                self.cipher,
+                NAMES_KEY_AD,
+            )?)),
+            _ => None,
+        };
+        let key = Some(key);
+        self.cipher = cipher;
+        self.key_check = Some(helpers::seal_value(
+            &KEY_CHECK.to_string(),
+            &key,
+            cipher,
+            KEY_CHECK_AD,
+        )?);
+        if self.blind_names {
+            let names_key = names_key.unwrap_or_else(|| SecVec::new(backend::gen_key()));
+            self.names_key = Some(helpers::seal_value(
+                &names_key.unsecure(),
+                &key,
+                cipher,
+                NAMES_KEY_AD,
+            )?);
+        }
+        self.pwd = key;
+        self.is_locked = false;
+        Ok(())
+    }
+
+    /// Starts blinding names, creating the names key if the data key is already known.
+    pub(crate) fn blind_names(&mut self) -> Result<()> {
+        self.blind_names = true;
+        if let (None, Some(_)) = (&self.names_key, &self.pwd) {
+            let names_key = backend::gen_key();
+            self.names_key = Some(helpers::seal_value(
+                &names_key,
+                &self.pwd,
+                self.cipher,
+                NAMES_KEY_AD,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Returns the key names are blinded with, `None` unless the store blinds names and is
+    /// encrypted.
+    pub(crate) fn names_key(&self) -> Result<Option<SecStr>> {
+        if !self.blind_names {
+            return Ok(None);
+        }
+        match (&self.names_key, self.pwd()?) {
+            (Some(sealed), Some(_)) => Ok(Some(SecVec::new(helpers::open_value(
+                sealed,
+                &self.pwd,
+                self.cipher,
+                NAMES_KEY_AD,
+            )?))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Keyring `key` of `namespace` is sealed under, if the namespace has a key of its own. The
+    /// entry keeping the name of a blinded namespace is always sealed under the store key, so
+    /// that namespaces can be listed without their keys.
+    pub(crate) fn namespace_keyring(&self, namespace: &str, key: &str) -> Option<&Keyring> {
+        if self.blind_names && key == NAMESPACE_ENTRY {
+            return None;
+        }
+        self.namespaces.get(namespace)
+    }
+
+    /// Public key `key` is sealed to, if the store is a drop box. Like in namespaces with a key
+    /// of their own, the entry keeping the name of a blinded namespace is sealed under the store
+    /// key.
+    pub(crate) fn drop_box(&self, key: &str) -> Option<&[u8]> {
+        if self.blind_names && key == NAMESPACE_ENTRY {
+            return None;
+        }
+        self.public_key.as_deref()
+    }
+
+    /// Returns the secret key of a drop box, needed to open its values.
+    pub(crate) fn secret_key(&self) -> Result<&SecStr> {
+        self.secret_key.as_ref().ok_or_else(|| KVError {
+            error: ErrorType::InvalidPassword,
+            msg: Some("store is a drop box, and its secret key was not given".to_string()),
+        })
+    }
+
+    /// Returns the key and cipher `key` of `namespace` is sealed with: the data key of the
+    /// namespace if it has its own, that of the store otherwise.
+    pub(crate) fn value_key(&self, namespace: &str, key: &str) -> Result<(Option<SecStr>, Cipher)> {
+        match self.namespace_keyring(namespace, key) {
+            Some(keyring) => match keyring.ensure_unlocked() {
+                Ok(()) => Ok((keyring.pwd.clone(), keyring.cipher)),
+                Err(_) => Err(KVError {
+                    error: ErrorType::InvalidPassword,
+                    msg: Some("namespace has its own key, and is locked".to_string()),
+                }),
+            },
+            None => Ok((self.pwd()?.clone(), self.cipher)),
+        }
+    }
+
+    /// Whether values of `namespace` can't be read, as the store or the namespace is locked.
+    pub(crate) fn is_namespace_locked(&self, namespace: &str) -> bool {
+        match self.namespaces.get(namespace) {
+            Some(keyring) => keyring.ensure_unlocked().is_err(),
+            None => self.ensure_unlocked().is_err(),
+        }
+    }
+
+    /// Returns the data key of an unlocked encrypted store.
+    pub(crate) fn data_key(&self) -> Result<SecStr> {
+        match self.pwd()? {
+            Some(key) => Ok(key.clone()),
+            None => Err(KVError {
+                error: ErrorType::KVError,
+                msg: Some("store is not encrypted".to_string()),
+            }),
+        }
+    }
+
+    /// Seals the data key of an unlocked store under `credential` in a new slot.
+    pub(crate) fn add_slot(&mut self, name: &str, credential: &Credential) -> Result<()> {
+        let data_key = self.data_key()?;
+        if self.slots.iter().any(|slot| slot.is_legacy()) {
+            return Err(KVError {
+                error: ErrorType::KVError,
+                msg: Some(
+                    "store uses a legacy key, change its password before adding key slots"
+                        .to_string(),
+                ),
+            });
+        }
+        if self.slots.iter().any(|slot| slot.name == name) {
+            return Err(KVError {
+                error: ErrorType::KVError,
+                msg: Some(format!("key slot `{}` already exists", name)),
+            });
+        }
+        let slot = KeySlot::seal(name, credential, self.kdf.renew(), &data_key, self.cipher)?;
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    /// Replaces the slot at `index` with one for `credential`, keeping its name.
+    pub(crate) fn replace_slot(
+        &mut self,
+        index: usize,
+        credential: &Credential,
+        kdf: Kdf,
+        data_key: &SecStr,
+    ) -> Result<()> {
+        let name = self.slots[index].name.clone();
+        self.slots[index] = KeySlot::seal(&name, credential, kdf, data_key, self.cipher)?;
+        Ok(())
+    }
+
+    /// Replaces every slot with a single one for `credential`. Used when the data key or the
+    /// cipher changes.
+    pub(crate) fn reset_slots(
+        &mut self,
+        credential: &Credential,
+        kdf: Kdf,
+        data_key: &SecStr,
+    ) -> Result<()> {
+        self.slots = vec![KeySlot::seal(
+            DEFAULT_SLOT,
+            credential,
+            kdf,
+            data_key,
+            self.cipher,
+        )?];
+        Ok(())
+    }
+
+    /// Removes a slot by name. The last slot can't be removed, as nothing could unlock the store.
+    pub(crate) fn remove_slot(&mut self, name: &str) -> Result<()> {
+        self.ensure_unlocked()?;
+        let index = match self.slots.iter().position(|slot| slot.name == name) {
+            Some(index) => index,
+            None => {
+                return Err(KVError {
+                    error: ErrorType::KVError,
+                    msg: Some(format!("key slot `{}` not found", name)),
+                })
+            }
+        };
+        if self.slots.len() == 1 {
+            return Err(KVError {
+                error: ErrorType::KVError,
+                msg: Some("cannot remove the last key slot".to_string()),
+            });
+        }
+        self.slots.remove(index);
+        Ok(())
+    }
+}
+
+// coerce a secure zero wipe
+impl Drop for Keyring {
+    fn drop(&mut self) {
+        if let Some(ref mut pwd) = self.pwd {
+            pwd.zero_out()
+        }
+        if let Some(ref mut secret_key) = self.secret_key {
+            secret_key.zero_out()
+        }
+    }
+}
@@ -50,22 +50,30 @@
 #![allow(clippy::result_map_unit_fn)]
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
-use secstr::{SecStr, SecVec};
+use secstr::SecStr;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use sodiumoxide::crypto::hash::sha256;
-use sodiumoxide::crypto::secretbox::{self, Nonce};
 
+use crate::cipher::Cipher;
+use crate::dropbox;
+use crate::encoding::{Compression, Padding};
 use crate::errors::{ErrorType, KVError, Result};
 use crate::helpers;
-use crate::migrate::Migrate;
-use crate::namespace::NamespaceMicroKV;
+use crate::history::NAMESPACE_ENTRY;
+use crate::kdf::Kdf;
+use crate::keyring::{Credential, KeySlot, SlotKind};
+use crate::lock::LockMode;
+use crate::merge::{Conflict, ConflictPolicy};
+use crate::namespace::{NamespaceInfo, NamespaceMicroKV};
+use crate::options::OpenOptions;
+use crate::provider::{KeyProvider, KeyfileProvider};
+use crate::transaction::Transaction;
 
 pub type Value = serde_json::Value;
-pub type MicroKV = crate::history::MicroKV030;
+pub type MicroKV = crate::history::MicroKV040;
 
 impl MicroKV {
     /// New MicroKV store with store to base path
@@ -75,13 +83,10 @@ impl MicroKV {
         // no password, until set by `with_pwd_*` methods
         let pwd: Option<SecStr> = None;
 
-        // initialize a new public nonce for symmetric AEAD
-        let nonce: Nonce = secretbox::gen_nonce();
-
         // get abspath to dbname to write to.
         let path = helpers::get_db_path_with_base_path(dbname, base_path);
 
-        Self::create(path, pwd, nonce, false, storage)
+        Self::create(path, Kdf::default(), pwd, false, storage)
     }
 
     /// Initializes a new empty and unencrypted MicroKV store with
@@ -93,31 +98,41 @@ impl MicroKV {
         Self::new_with_base_path(dbname, path)
     }
 
-    /// Open with base path
+    /// Open with base path. Opening reads the store and never rewrites it, unless it is in
+    /// the layout of an older version, see `OpenOptions` for finer control.
     pub fn open_with_base_path<S: AsRef<str>>(dbname: S, base_path: PathBuf) -> Result<Self> {
-        // initialize abspath to persistent db
-        let path = helpers::get_db_path_with_base_path(dbname.as_ref(), base_path.clone());
-
-        if path.is_file() {
-            let migrate = Migrate::new(path.clone());
-            let mut kv = migrate.migrate()?;
-            kv.path = path;
-            kv.commit()?;
-            Ok(kv)
-        } else {
-            Ok(Self::new_with_base_path(dbname, base_path))
-        }
+        OpenOptions::new().open_with_base_path(dbname, base_path)
+    }
+
+    /// Like `open_with_base_path`, and unlocks the store with `unsafe_pwd`. Encrypted stores
+    /// written before 0.3.0 must be opened this way to be migrated, as their values are
+    /// decrypted to be moved over. Stores written by 0.3.0 are sealed again as they are
+    /// migrated, see `migrate::V030To040`.
+    pub fn open_with_base_path_and_pwd<S: AsRef<str>, P: AsRef<str>>(
+        dbname: S,
+        base_path: PathBuf,
+        unsafe_pwd: P,
+    ) -> Result<Self> {
+        OpenOptions::new()
+            .with_pwd_clear(unsafe_pwd)
+            .open_with_base_path(dbname, base_path)
     }
 
     /// Opens a previously instantiated and encrypted MicroKV, given a db name.
-    /// The public nonce generated from a previous session is also retrieved in order to
-    /// do authenticated encryption later on.
+    /// Stores written by older versions are migrated to the current layout.
     pub fn open<S: AsRef<str>>(dbname: S) -> Result<Self> {
         let mut path = helpers::get_home_dir();
         path.push(helpers::DEFAULT_WORKSPACE_PATH);
         Self::open_with_base_path(dbname, path)
     }
 
+    /// Like `open`, and unlocks the store with `unsafe_pwd`, see `open_with_base_path_and_pwd`.
+    pub fn open_with_pwd<S: AsRef<str>, P: AsRef<str>>(dbname: S, unsafe_pwd: P) -> Result<Self> {
+        let mut path = helpers::get_home_dir();
+        path.push(helpers::DEFAULT_WORKSPACE_PATH);
+        Self::open_with_base_path_and_pwd(dbname, path, unsafe_pwd)
+    }
+
     /*
     /// `override_path()` changes the default path for persisting the store, rather than
     /// writing/reading from the default workspace directory.
@@ -127,25 +142,216 @@ impl MicroKV {
     }
     */
 
-    /// Builds up the MicroKV with a cleartext password, which is hashed using
-    /// the defaultly supported SHA-256 by `sodiumoxide`, in order to instantiate a 32-byte hash.
+    /// Builds up the MicroKV with a cleartext password, which is stretched into a 32-byte key
+    /// with the KDF recorded in its key slot (Argon2id with a random salt for new stores).
     ///
     /// Use if the password to encrypt is not naturally pseudorandom and secured in-memory,
     /// and is instead read elsewhere, like a file or stdin (developer should guarentee security when
     /// implementing such methods, as MicroKV only guarentees hashing and secure storage).
-    pub fn with_pwd_clear<S: AsRef<str>>(mut self, unsafe_pwd: S) -> Self {
-        let pwd: SecStr = SecVec::new(sha256::hash(unsafe_pwd.as_ref().as_bytes()).0.to_vec());
-        self.pwd = Some(pwd);
-        self
+    ///
+    /// If the password does not unlock an existing store, every later read and write fails with
+    /// `ErrorType::InvalidPassword`. Use `try_with_pwd_clear` to find out immediately.
+    pub fn with_pwd_clear<S: AsRef<str>>(self, unsafe_pwd: S) -> Self {
+        self.with_credential(Credential::password(unsafe_pwd))
     }
 
     /// Builds up the MicroKV with a hashed buffer, which is then locked securely `for later use.
     ///
     /// Use if the password to encrypt is generated as a pseudorandom value, or previously hashed by
     /// another preferred one-way function within or outside the application.
-    pub fn with_pwd_hash(mut self, _pwd: [u8; 32]) -> Self {
-        let pwd: SecStr = SecVec::new(_pwd.to_vec());
-        self.pwd = Some(pwd);
+    pub fn with_pwd_hash(self, _pwd: [u8; 32]) -> Self {
+        self.with_credential(Credential::hash(_pwd))
+    }
+
+    /// Builds up the MicroKV with the contents of a keyfile, which are stretched like a password.
+    pub fn with_keyfile<P: AsRef<Path>>(self, path: P) -> Self {
+        self.with_key_provider(&KeyfileProvider::new(path.as_ref()))
+    }
+
+    /// Builds up the MicroKV with any credential accepted by a key slot.
+    pub fn with_credential(self, credential: Credential) -> Self {
+        // a failed unlock is remembered, so that values can't be read or written with a wrong key
+        let _ = self.unlock(&credential);
+        self
+    }
+
+    /// Builds up the MicroKV with the credential read from `provider`, e.g. an environment
+    /// variable or a keyfile. If it can't be read, every later read and write fails as if a
+    /// wrong password was given.
+    pub fn with_key_provider<P: KeyProvider>(self, provider: &P) -> Self {
+        match provider.credential() {
+            Ok(credential) => self.with_credential(credential),
+            Err(_) => {
+                if let Ok(mut keyring) = self.keyring_mut() {
+                    keyring.lock();
+                }
+                self
+            }
+        }
+    }
+
+    /// Like `with_pwd_clear`, but fails with `ErrorType::InvalidPassword` straight away if the
+    /// password does not unlock the store.
+    pub fn try_with_pwd_clear<S: AsRef<str>>(self, unsafe_pwd: S) -> Result<Self> {
+        self.try_with_credential(Credential::password(unsafe_pwd))
+    }
+
+    /// Like `with_pwd_hash`, but fails with `ErrorType::InvalidPassword` straight away if the
+    /// hash does not unlock the store.
+    pub fn try_with_pwd_hash(self, _pwd: [u8; 32]) -> Result<Self> {
+        self.try_with_credential(Credential::hash(_pwd))
+    }
+
+    /// Like `with_keyfile`, but fails straight away if the keyfile can't be read or does not
+    /// unlock the store.
+    pub fn try_with_keyfile<P: AsRef<Path>>(self, path: P) -> Result<Self> {
+        self.try_with_key_provider(&KeyfileProvider::new(path.as_ref()))
+    }
+
+    /// Like `with_key_provider`, but fails with `ErrorType::ProviderError` straight away if the
+    /// credential can't be read, and with `ErrorType::InvalidPassword` if it does not unlock
+    /// the store.
+    pub fn try_with_key_provider<P: KeyProvider>(self, provider: &P) -> Result<Self> {
+        self.try_with_credential(provider.credential()?)
+    }
+
+    /// Like `with_credential`, but fails with `ErrorType::InvalidPassword` straight away if the
+    /// credential does not unlock the store.
+    pub fn try_with_credential(self, credential: Credential) -> Result<Self> {
+        self.unlock(&credential)?;
+        Ok(self)
+    }
+
+    /// Sets the KDF used to derive the key from a cleartext password or keyfile, e.g. to raise
+    /// the Argon2id cost parameters. Must be called on a new store, before `with_pwd_clear`;
+    /// slots of opened stores keep the KDF they were created with until `upgrade_kdf` is called.
+    pub fn with_kdf(self, kdf: Kdf) -> Self {
+        if let Ok(mut keyring) = self.keyring_mut() {
+            keyring.kdf = kdf;
+        }
+        self
+    }
+
+    /// Sets the cipher values and key slots are sealed with, e.g. `Cipher::Aes256Gcm` where
+    /// AES is required. Must be called on a new store, before `with_pwd_clear`; it is ignored
+    /// on a store that is already encrypted, which keeps the cipher recorded in its header.
+    /// `Cipher::XSalsa20Poly1305` is only kept to read stores created before 0.4.0, and is
+    /// ignored as well.
+    pub fn with_cipher(self, cipher: Cipher) -> Self {
+        if let Ok(mut keyring) = self.keyring_mut() {
+            if !keyring.is_encrypted() && !cipher.is_legacy() {
+                keyring.cipher = cipher;
+            }
+        }
+        self
+    }
+
+    /// Blinds namespace and key names with a keyed hash, so that the store file does not show
+    /// which secrets it holds. The original names are sealed alongside each entry, so `keys()`,
+    /// `sorted_keys()` and `namespaces()` keep working once the store is unlocked.
+    ///
+    /// Must be called on a new store before any value is written, and only takes effect once
+    /// the store is encrypted. It is ignored on a store that already holds values.
+    pub fn with_blind_names(self) -> Self {
+        if let (true, Ok(mut keyring)) = (self.holds_no_values(), self.keyring_mut()) {
+            let _ = keyring.blind_names();
+        }
+        self
+    }
+
+    /// Pads values to size buckets before they are sealed, so that the store file does not show
+    /// how long each one is. Only values written afterwards are padded; the setting is recorded
+    /// in the store header.
+    pub fn with_padding(self, padding: Padding) -> Self {
+        if let Ok(mut keyring) = self.keyring_mut() {
+            keyring.padding = padding;
+        }
+        self
+    }
+
+    /// Compresses large values, such as JSON blobs, before they are padded and sealed. Only
+    /// values written afterwards are compressed; the setting is recorded in the store header.
+    /// See `crate::encoding` before compressing secrets along with untrusted data.
+    pub fn with_compression(self, compression: Compression) -> Self {
+        if let Ok(mut keyring) = self.keyring_mut() {
+            keyring.compression = compression;
+        }
+        self
+    }
+
+    /// Turns the store into a drop box for the X25519 `public_key`, e.g. from
+    /// `dropbox::gen_keypair`: every value is sealed to it, so that writers can `put` values
+    /// they can't read back. Reading them needs the matching secret key, see `with_secret_key`.
+    ///
+    /// Must be called on a new store before any value is written. It is ignored on a store that
+    /// already holds values, which keeps the public key recorded in its header, if any.
+    pub fn with_public_key(self, public_key: [u8; 32]) -> Self {
+        if let (true, Ok(mut keyring)) = (self.holds_no_values(), self.keyring_mut()) {
+            keyring.public_key = Some(public_key.to_vec());
+        }
+        self
+    }
+
+    /// Builds up a drop box with the secret key its values are read with. A secret key that
+    /// does not match the public key of the store is ignored, and values can't be read.
+    pub fn with_secret_key(self, secret_key: SecStr) -> Self {
+        let _ = self.set_secret_key(secret_key);
+        self
+    }
+
+    /// Like `with_secret_key`, but fails with `ErrorType::InvalidPassword` straight away if the
+    /// store is not a drop box, or if the secret key does not match its public key.
+    pub fn try_with_secret_key(self, secret_key: SecStr) -> Result<Self> {
+        self.set_secret_key(secret_key)?;
+        Ok(self)
+    }
+
+    fn set_secret_key(&self, secret_key: SecStr) -> Result<()> {
+        let mut keyring = self.keyring_mut()?;
+        let public_key = dropbox::public_key(&secret_key)?;
+        if keyring.public_key.as_deref() != Some(&public_key[..]) {
+            return Err(KVError {
+                error: ErrorType::InvalidPassword,
+                msg: Some("secret key does not match the public key of the store".to_string()),
+            });
+        }
+        keyring.secret_key = Some(secret_key);
+        Ok(())
+    }
+
+    /// Whether every namespace of the store is empty.
+    fn holds_no_values(&self) -> bool {
+        let is_empty = self.storage.read().map(|storage| {
+            storage
+                .values()
+                .all(|data| data.read().map(|data| data.is_empty()).unwrap_or(false))
+        });
+        is_empty.unwrap_or(false)
+    }
+
+    /// Sets how reloads and commits wait for another process holding the lock on the store
+    /// file: until it is released, which is the default, not at all, or up to a timeout. Both
+    /// fail with `ErrorType::LockError` when they give up.
+    pub fn with_lock_mode(mut self, mode: LockMode) -> Self {
+        self.lock_mode = mode;
+        self
+    }
+
+    /// Sets how reloads and commits settle an entry changed both by this handle, which has not
+    /// committed it yet, and by another handle, which has: by keeping the change of this handle
+    /// until it is committed, which is the default, or the one committed by the other handle.
+    pub fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    /// Sets a handler called with every conflict a reload or commit settles. It is called once
+    /// the reload or commit is done, and may use the store.
+    pub fn with_conflict_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Conflict) + Send + Sync + 'static,
+    {
+        self.conflict_handler = Some(Arc::new(handler));
         self
     }
 
@@ -155,17 +361,186 @@ impl MicroKV {
         self
     }
 
+    /// Sets whether auto-commits of `put` and `delete` append the entries they changed to a
+    /// write-ahead log next to the store, rather than rewriting the whole store file. The log
+    /// is replayed whenever the store is read, and compacted into the store file by any commit.
+    pub fn set_write_ahead_log(mut self, enable: bool) -> Self {
+        self.write_ahead_log = enable;
+        self
+    }
+
+    /// Writes the whole store, with the records of its write-ahead log, to the store file, and
+    /// starts an empty log. This is what any commit does.
+    pub fn compact(&self) -> Result<()> {
+        self.commit()
+    }
+
+    /// Whether the store is encrypted and has not been unlocked with the right password.
+    pub fn is_locked(&self) -> bool {
+        match self.keyring() {
+            Ok(keyring) => keyring.ensure_unlocked().is_err(),
+            Err(_) => true,
+        }
+    }
+
+    /// Whether the store was opened read-only, and rejects any change.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the public key values are sealed to, if the store is a drop box.
+    pub fn public_key(&self) -> Result<Option<[u8; 32]>> {
+        let keyring = self.keyring()?;
+        Ok(keyring.public_key.as_ref().map(|public_key| {
+            let mut bytes = [0; 32];
+            bytes.copy_from_slice(public_key);
+            bytes
+        }))
+    }
+
+    /// Returns the cipher recorded in the store header.
+    pub fn cipher(&self) -> Result<Cipher> {
+        Ok(self.keyring()?.cipher)
+    }
+
+    /// Returns the KDF used for the first password of a new store, and for new key slots.
+    pub fn kdf(&self) -> Result<Kdf> {
+        Ok(self.keyring()?.kdf.clone())
+    }
+
+    /// Moves the key slot opened by `pwd` onto a new KDF, e.g. from the legacy SHA-256
+    /// derivation to Argon2id, and commits the store. Fails with `ErrorType::InvalidPassword`
+    /// if `pwd` does not unlock the store.
+    ///
+    /// Stores created before 0.4.0 used the key derived from the password to seal values, so
+    /// every value is sealed again under a new random data key.
+    pub fn upgrade_kdf<S: AsRef<str>>(&self, pwd: S, kdf: Kdf) -> Result<()> {
+        let credential = Credential::password(pwd);
+        self.replace_credential(&credential, &credential, Some(kdf))
+    }
+
+    /// Changes the password of the key slot opened by `old` to `new`, with a fresh salt, and
+    /// commits the store. Only that slot is sealed again; values and other slots are left as
+    /// they are. Fails with `ErrorType::InvalidPassword` if `old` does not unlock the store.
+    ///
+    /// Stores created before 0.4.0 are moved to Argon2id, and every value is sealed again
+    /// under a new random data key.
+    pub fn change_password<S: AsRef<str>>(&self, old: S, new: S) -> Result<()> {
+        self.change_credential(&Credential::password(old), &Credential::password(new))
+    }
+
+    /// Like `change_password`, for any kind of credential, e.g. to move a slot from a
+    /// password onto a keyfile.
+    pub fn change_credential(&self, old: &Credential, new: &Credential) -> Result<()> {
+        self.replace_credential(old, new, None)
+    }
+
+    /// Re-keys an unlocked store with a new hashed buffer, as given to `with_pwd_hash`. Every
+    /// value in every namespace is sealed again under a new random data key, every key slot is
+    /// replaced with a single one for the hash, and the store is committed. An unencrypted
+    /// store is encrypted.
+    pub fn rekey_with_pwd_hash(&self, _pwd: [u8; 32]) -> Result<()> {
+        let (old, kdf) = {
+            let keyring = self.keyring()?;
+            (keyring.pwd()?.clone(), keyring.kdf.clone())
+        };
+        self.rekey(&old, &Credential::hash(_pwd), kdf)?;
+        self.commit()
+    }
+
+    fn replace_credential(
+        &self,
+        old: &Credential,
+        new: &Credential,
+        kdf: Option<Kdf>,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let index = self.unlock(old)?;
+        let mut keyring = self.keyring_mut()?;
+        if let Some(ref kdf) = kdf {
+            keyring.kdf = kdf.clone();
+        }
+        let slot = &keyring.slots[index];
+        let kdf = match (kdf, &slot.kind) {
+            (Some(kdf), _) => kdf,
+            (None, SlotKind::Password(kdf)) | (None, SlotKind::Keyfile(kdf)) => kdf.renew(),
+            (None, SlotKind::Hash) | (None, SlotKind::Wrapper { .. }) => keyring.kdf.renew(),
+        };
+        if slot.is_legacy() {
+            let old = keyring.pwd()?.clone();
+            drop(keyring);
+            self.rekey(&old, new, kdf)?;
+        } else {
+            let data_key = keyring.data_key()?;
+            keyring.replace_slot(index, new, kdf, &data_key)?;
+            drop(keyring);
+        }
+        self.commit()
+    }
+
+    /// Adds a key slot, so that the store can also be unlocked with `credential`, and commits
+    /// the store. The store must be unlocked, and `name` must not be taken by another slot.
+    pub fn add_key_slot(&self, name: impl AsRef<str>, credential: Credential) -> Result<()> {
+        self.ensure_writable()?;
+        self.keyring_mut()?.add_slot(name.as_ref(), &credential)?;
+        self.commit()
+    }
+
+    /// Removes a key slot, so that its credential no longer unlocks the store, and commits the
+    /// store. Values are not sealed again, so whoever held the credential and kept a copy of
+    /// the store or its data key can still read that copy; use `rekey_with_pwd_hash` for that.
+    pub fn remove_key_slot(&self, name: impl AsRef<str>) -> Result<()> {
+        self.ensure_writable()?;
+        self.keyring_mut()?.remove_slot(name.as_ref())?;
+        self.commit()
+    }
+
+    /// Lists the key slots of the store.
+    pub fn key_slots(&self) -> Result<Vec<KeySlot>> {
+        Ok(self.keyring()?.slots.clone())
+    }
+
     ///////////////////////////////////////
     // extended
     ///////////////////////////////////////
 
+    /// Lists the names of the namespaces of the store.
     pub fn namespaces(&self) -> Result<Vec<String>> {
+        let namespaces = self.namespace_infos()?;
+        Ok(namespaces.into_iter().map(|info| info.name).collect())
+    }
+
+    /// Lists the namespaces of the store, and whether each of them is locked.
+    pub fn namespace_infos(&self) -> Result<Vec<NamespaceInfo>> {
+        let blinds_names = self.blinds_names()?;
         let storage = self.storage.read().map_err(|_| KVError {
             error: ErrorType::PoisonError,
             msg: None,
         })?;
-        let keys = storage.keys().cloned().collect::<Vec<String>>();
-        Ok(keys)
+        let mut namespaces = Vec::new();
+        for (namespace, data) in storage.iter() {
+            let name = match blinds_names {
+                false => namespace.clone(),
+
+                // blinded namespaces keep their original name in a reserved entry
+                true => {
+                    let data = data.read().map_err(|_| KVError {
+                        error: ErrorType::PoisonError,
+                        msg: None,
+                    })?;
+                    match data.get(NAMESPACE_ENTRY) {
+                        Some(entry) => {
+                            let name = self.decode_value(namespace, NAMESPACE_ENTRY, entry)?;
+                            serde_json::from_value(name)?
+                        }
+                        None => continue,
+                    }
+                }
+            };
+            let is_locked = self.keyring()?.is_namespace_locked(namespace);
+            namespaces.push(NamespaceInfo { name, is_locked });
+        }
+        Ok(namespaces)
     }
 
     pub fn namespace(&self, namespace: impl AsRef<str>) -> NamespaceMicroKV {
@@ -176,6 +551,31 @@ impl MicroKV {
         self.namespace("")
     }
 
+    /// Runs `f` in a transaction, whose writes, across any namespaces, are applied and
+    /// persisted all at once when it returns `Ok`, and dropped when it returns an error, see
+    /// `crate::transaction`.
+    ///
+    /// ```rust
+    /// use microkv::MicroKV;
+    ///
+    /// let kv: MicroKV = MicroKV::new("example").with_pwd_clear("p@ssw0rd".to_string());
+    /// kv.transaction(|tx| {
+    ///     tx.namespace("oauth").put("client_id", &"id")?;
+    ///     tx.namespace("oauth").put("client_secret", &"secret")?;
+    ///     tx.namespace("legacy").delete("token")
+    /// })
+    /// .expect("cannot apply transaction");
+    /// ```
+    pub fn transaction<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Transaction) -> Result<R>,
+    {
+        let transaction = Transaction::new(self);
+        let ret = f(&transaction)?;
+        transaction.apply()?;
+        Ok(ret)
+    }
+
     ///////////////////////////////////////
     // Primitive key-value store operations
     ///////////////////////////////////////
@@ -252,12 +652,3 @@ impl MicroKV {
         self.namespace_default().clear()
     }
 }
-
-// coerce a secure zero wipe
-impl Drop for MicroKV {
-    fn drop(&mut self) {
-        if let Some(ref mut pwd) = self.pwd {
-            pwd.zero_out()
-        }
-    }
-}
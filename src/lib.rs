@@ -18,11 +18,25 @@
 // re-import for accessible namespace
 pub use self::kv::MicroKV;
 
+pub mod cipher;
+pub mod dropbox;
+pub mod encoding;
 pub mod errors;
+pub mod format;
 pub mod helpers;
 pub mod history;
+pub mod kdf;
+pub mod keyring;
 pub mod kv;
+pub mod lock;
+pub mod merge;
+pub mod migrate;
 pub mod namespace;
+pub mod options;
+pub mod provider;
+pub mod transaction;
 pub mod types;
 
-mod migrate;
+mod backend;
+mod integrity;
+mod wal;
@@ -0,0 +1,132 @@
+//! Defines the advisory file lock that keeps processes sharing a store file from interleaving
+//! their reads and writes. Reloads hold it shared and commits hold it exclusively, set with
+//! `MicroKV::with_lock_mode` to wait for it, give up straight away, or wait up to a timeout.
+//!
+//! Commits replace the store file by renaming a new one over it, so the lock is taken on a
+//! sibling `<store>.lock` file instead, which is never replaced. Within a process, it is always
+//! taken after the locks on the storage map and integrity block of the store, and released
+//! before them. Commits read the store file again under it, to merge what other processes
+//! committed since.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use crate::errors::{ErrorType, KVError, Result};
+
+/// Delay between attempts to take a contended lock, with `LockMode::Timeout`.
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How reloads and commits wait for a lock held by another process.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LockMode {
+    /// wait until the lock is released
+    #[default]
+    Blocking,
+
+    /// fail with `ErrorType::LockError` straight away if the lock is held
+    Try,
+
+    /// wait up to the given duration, then fail with `ErrorType::LockError`
+    Timeout(Duration),
+}
+
+/// Advisory lock on a store file, released when dropped.
+pub(crate) struct FileLock {
+    file: Option<File>,
+}
+
+impl FileLock {
+    /// Takes a shared lock to read the store at `path`. Nothing is locked if the directory of
+    /// the store does not exist, as there is nothing to read.
+    ///
+    /// With `read_only`, the lock file is opened read-only and never created, so that nothing
+    /// is written, e.g. on read-only media. Nothing is locked if it does not exist yet.
+    pub(crate) fn shared(path: &Path, mode: LockMode, read_only: bool) -> Result<Self> {
+        let lock_path = lock_path(path);
+        if !lock_path.parent().map(Path::is_dir).unwrap_or(false) {
+            return Ok(Self { file: None });
+        }
+        if read_only {
+            return match File::open(&lock_path) {
+                Ok(file) => Self::lock_file(file, &lock_path, mode, false),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self { file: None }),
+                Err(e) => Err(e.into()),
+            };
+        }
+        Self::lock(&lock_path, mode, false)
+    }
+
+    /// Takes an exclusive lock to write the store at `path`, creating its directory if needed.
+    pub(crate) fn exclusive(path: &Path, mode: LockMode) -> Result<Self> {
+        let lock_path = lock_path(path);
+        if let Some(dir) = lock_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Self::lock(&lock_path, mode, true)
+    }
+
+    fn lock(lock_path: &Path, mode: LockMode, exclusive: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path)?;
+        Self::lock_file(file, lock_path, mode, exclusive)
+    }
+
+    // `FileExt` is named explicitly, as `File` has methods of the same names since Rust 1.89
+    fn lock_file(file: File, lock_path: &Path, mode: LockMode, exclusive: bool) -> Result<Self> {
+        let deadline = match mode {
+            LockMode::Blocking => {
+                match exclusive {
+                    true => FileExt::lock_exclusive(&file)?,
+                    false => FileExt::lock_shared(&file)?,
+                }
+                return Ok(Self { file: Some(file) });
+            }
+            LockMode::Try => Instant::now(),
+            LockMode::Timeout(timeout) => Instant::now() + timeout,
+        };
+        loop {
+            let locked = match exclusive {
+                true => FileExt::try_lock_exclusive(&file),
+                false => FileExt::try_lock_shared(&file),
+            };
+            match locked {
+                Ok(()) => return Ok(Self { file: Some(file) }),
+                Err(e) if e.kind() != fs2::lock_contended_error().kind() => return Err(e.into()),
+                Err(_) if Instant::now() >= deadline => {
+                    return Err(KVError {
+                        error: ErrorType::LockError,
+                        msg: Some(format!(
+                            "store file {:?} is locked by another process",
+                            lock_path
+                        )),
+                    })
+                }
+                Err(_) => thread::sleep(RETRY_DELAY),
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Some(ref file) = self.file {
+            let _ = FileExt::unlock(file);
+        }
+    }
+}
+
+/// Path of the file locked for the store at `path`.
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
@@ -0,0 +1,129 @@
+//! Defines how a reload merges the store file into the entries of a handle. Entries are merged
+//! one by one rather than namespace by namespace: entries the handle changed and has not
+//! committed yet are kept over those of the store file, and every other entry is taken from the
+//! store file. A commit merges the store file the same way first, under the lock it writes the
+//! store file with, if another handle committed since this one last read it.
+//!
+//! Each entry carries a version, counting the puts made to it, and the handle remembers the
+//! version an entry had when it first changed it. If the store file holds another version by
+//! the next reload or commit, another handle committed a change to the same entry in between:
+//! this is a conflict, settled by the `ConflictPolicy` set with `MicroKV::with_conflict_policy`,
+//! and reported to the handler set with `MicroKV::with_conflict_handler`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use crate::errors::{ErrorType, KVError, Result};
+use crate::types::{Storage, KV};
+
+/// How a reload or commit settles an entry changed both by this handle, which has not committed
+/// it yet, and by another handle, which has.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConflictPolicy {
+    /// the change of this handle is kept until it is committed, and then replaces the other
+    #[default]
+    KeepLocal,
+
+    /// the change committed by the other handle is kept, and the one of this handle dropped
+    KeepStored,
+}
+
+/// Entry changed both by this handle and by another one, as reported to a conflict handler.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    /// namespace of the entry as stored, blinded if the store blinds names
+    pub namespace: String,
+
+    /// key of the entry as stored, blinded if the store blinds names
+    pub key: String,
+
+    /// version of the entry when this handle changed it, 0 if it did not exist
+    pub local_version: u64,
+
+    /// version of the entry committed by the other handle, 0 if it was deleted
+    pub stored_version: u64,
+
+    /// whether the change of this handle was kept
+    pub kept_local: bool,
+}
+
+/// Called with every conflict a reload or commit settles, once it is done.
+pub type ConflictHandler = Arc<dyn Fn(&Conflict) + Send + Sync>;
+
+/// Entries changed by a handle and not committed yet, by namespace and key as stored, with the
+/// version each had before.
+pub(crate) type Dirty = HashMap<String, HashMap<String, u64>>;
+
+/// Merges `theirs`, the namespaces of the store file, with `ours`, the namespaces of this
+/// handle, keeping the `dirty` entries of this handle as `policy` says. Marks of entries whose
+/// change was dropped are removed from `dirty`. Returns the merged namespaces, and the
+/// conflicts that were settled.
+pub(crate) fn merge(
+    ours: &HashMap<String, Storage>,
+    theirs: &HashMap<String, Storage>,
+    dirty: &mut Dirty,
+    policy: ConflictPolicy,
+) -> Result<(HashMap<String, Storage>, Vec<Conflict>)> {
+    let mut merged: HashMap<String, KV> = HashMap::new();
+    for (namespace, storage) in theirs.iter() {
+        merged.insert(namespace.clone(), read(storage)?.clone());
+    }
+
+    let mut conflicts = Vec::new();
+    for (namespace, keys) in dirty.iter_mut() {
+        let local = match ours.get(namespace) {
+            Some(storage) => Some(read(storage)?),
+            None => None,
+        };
+        let data = merged.entry(namespace.clone()).or_default();
+        keys.retain(|key, version| {
+            let stored_version = data.get(key).map(|value| value.version).unwrap_or(0);
+            let is_conflict = stored_version != *version;
+            let kept_local = !is_conflict || policy == ConflictPolicy::KeepLocal;
+            if is_conflict {
+                conflicts.push(Conflict {
+                    namespace: namespace.clone(),
+                    key: key.clone(),
+                    local_version: *version,
+                    stored_version,
+                    kept_local,
+                });
+            }
+            if !kept_local {
+                return false;
+            }
+
+            match local.as_ref().and_then(|local| local.get(key)) {
+                Some(value) => {
+                    // a kept change must read as newer than the one it replaces, once committed
+                    let mut value = value.clone();
+                    value.version = value.version.max(stored_version + 1);
+                    data.insert(key.clone(), value);
+                }
+                None => {
+                    let _ = data.remove(key);
+                }
+            }
+            // the conflict is settled, and only reported again if the entry changes anew
+            *version = stored_version;
+            true
+        });
+        if data.is_empty() && local.is_none() {
+            merged.remove(namespace);
+        }
+    }
+    dirty.retain(|_, keys| !keys.is_empty());
+
+    let merged = merged
+        .into_iter()
+        .map(|(namespace, data)| (namespace, Arc::new(RwLock::new(data))))
+        .collect();
+    Ok((merged, conflicts))
+}
+
+fn read(storage: &Storage) -> Result<RwLockReadGuard<'_, KV>> {
+    storage.read().map_err(|_| KVError {
+        error: ErrorType::PoisonError,
+        msg: None,
+    })
+}
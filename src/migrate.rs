@@ -1,61 +1,652 @@
+//! Defines how store files written by older versions of microkv are migrated to the current
+//! layout. A store file is read into the `Store` of its layout version, then handed along a chain
+//! of `Migrator`s, each taking a store of one layout version to the next, until it reaches the
+//! current one. The built-in migrators take stores written before 0.3.0 to 0.3.0, and 0.3.0 to
+//! 0.4.0; `Migrate::with_migrator` registers others.
+//!
+//! Opening a store migrates it on the way, see `options::MigrateMode`. `Migrate::run` migrates a store
+//! file in place instead, or with `Migrate::set_dry_run`, only reports what would change. Before
+//! a store file is rewritten to a new layout, it is copied as it was to a `<store>.<secs>.bak`
+//! backup next to it, stamped with the seconds since the Unix epoch, or `<store>.<secs>.<n>.bak`
+//! if a backup was already taken within that second.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use secstr::{SecStr, SecVec};
 
+use crate::cipher::Cipher;
 use crate::errors::{ErrorType, KVError, Result};
-use crate::{helpers, history, MicroKV};
+use crate::format;
+use crate::helpers;
+use crate::kdf::Kdf;
+use crate::keyring::{Credential, KeySlot, SlotKind};
+use crate::kv::Value;
+use crate::lock::{FileLock, LockMode};
+use crate::types::{LegacyKV, SealedValue, Storage, KV};
+use crate::{history, MicroKV};
 
 const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// A store in one of the layouts microkv has written.
+pub enum Store {
+    /// written before 0.3.0, with a single map of values
+    Less030(history::MicroKVLess030),
+
+    /// written by 0.3.0, with values sealed under a store-wide nonce
+    V030(history::MicroKV030),
+
+    /// the current layout
+    V040(history::MicroKV040),
+}
+
+impl Store {
+    /// Layout version of the store, `<0.3.0` for stores written before 0.3.0, which don't
+    /// record it.
+    pub fn version(&self) -> &str {
+        match self {
+            Store::Less030(_) => "<0.3.0",
+            Store::V030(kv) => kv.version(),
+            Store::V040(kv) => kv.version(),
+        }
+    }
+}
+
+/// Takes a store of one layout version to the next.
+pub trait Migrator {
+    /// layout version of the stores it migrates
+    fn source_version(&self) -> &str;
+
+    /// layout version of the stores it returns
+    fn target_version(&self) -> &str;
+
+    /// Migrates `store`, of layout `source_version`, to `target_version`. `pwd` is the cleartext
+    /// password given to `Migrate::with_pwd_clear`, if any.
+    fn migrate(&self, store: Store, pwd: Option<&SecStr>) -> Result<Store>;
+}
+
+/// What a migration did, or with `Migrate::set_dry_run`, would do.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationReport {
+    /// path of the store file
+    pub path: PathBuf,
+
+    /// layout version the store file was in
+    pub from: String,
+
+    /// layout version the store was migrated to
+    pub to: String,
+
+    /// layout versions each migrator of the chain went from and to, in order
+    pub steps: Vec<(String, String)>,
+
+    /// number of namespaces of the migrated store
+    pub namespaces: usize,
+
+    /// number of entries of the migrated store, across its namespaces
+    pub entries: usize,
+
+    /// copy of the store file made before it was rewritten, `None` if it wasn't
+    pub backup: Option<PathBuf>,
+
+    /// whether the store file was left as it was
+    pub dry_run: bool,
+}
+
+impl MigrationReport {
+    /// Whether the store file was in an older layout.
+    pub fn is_migrated(&self) -> bool {
+        !self.steps.is_empty()
+    }
+}
+
 pub struct Migrate {
     path: PathBuf,
+
+    /// cleartext password, needed to migrate encrypted stores written before 0.3.0, and to
+    /// seal stores written by 0.3.0 again
+    pwd: Option<SecStr>,
+
+    /// only report what would change
+    dry_run: bool,
+
+    /// built-in migrator of stores written before 0.3.0, as set up by `set_legacy_encrypted`
+    /// and `set_legacy_values`
+    legacy: Less030To030,
+
+    /// migrators the chain is built from, after `legacy`, later ones taking precedence
+    migrators: Vec<Box<dyn Migrator>>,
 }
 
 impl Migrate {
     pub fn new(path: PathBuf) -> Self {
-        Self { path }
+        Self {
+            path,
+            pwd: None,
+            dry_run: false,
+            legacy: Less030To030::default(),
+            migrators: vec![Box::new(V030To040)],
+        }
+    }
+
+    /// Sets the password of the store, which is needed up front to migrate an encrypted store
+    /// written before 0.3.0, as its values must be decrypted to be moved over. Stores written
+    /// by 0.3.0 are sealed again with it, see `V030To040`.
+    pub fn with_pwd_clear<S: AsRef<str>>(self, unsafe_pwd: S) -> Self {
+        self.with_pwd(SecVec::new(unsafe_pwd.as_ref().as_bytes().to_vec()))
+    }
+
+    pub(crate) fn with_pwd(mut self, pwd: SecStr) -> Self {
+        self.pwd = Some(pwd);
+        self
+    }
+
+    /// Registers `migrator`, which is used over those registered before it for the same layout
+    /// version, including the built-in ones.
+    pub fn with_migrator<M: Migrator + 'static>(mut self, migrator: M) -> Self {
+        self.migrators.push(Box::new(migrator));
+        self
+    }
+
+    /// Sets whether the values of a store written before 0.3.0 are encrypted, which its layout
+    /// does not record. By default, they are taken as encrypted only if a password is given.
+    pub fn set_legacy_encrypted(mut self, encrypted: bool) -> Self {
+        self.legacy.encrypted = Some(encrypted);
+        self
+    }
+
+    /// Sets what the values of a store written before 0.3.0 were encoded from, which its layout
+    /// does not record, `LegacyValues::Strings` by default.
+    pub fn set_legacy_values(mut self, values: LegacyValues) -> Self {
+        self.legacy.values = values;
+        self
+    }
+
+    /// Sets whether `run` only reports what would change, leaving the store file as it is.
+    pub fn set_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
     }
 }
 
 impl Migrate {
-    pub fn migrate(&self) -> Result<MicroKV> {
-        let ret = self.try_current().or_else(|_e| self.try_less_than_030());
-        match ret {
-            Ok(v) => Ok(v),
-            Err(e) => match e.error {
-                ErrorType::MigrateError(from, to) => Err(KVError {
-                    error: ErrorType::MigrateError(from.clone(), to.clone()),
-                    msg: Some(format!(
-                        "Not support migrate {:?} from {} to {}",
-                        self.path, from, to
-                    )),
-                }),
-                _ => Err(KVError {
-                    error: ErrorType::MigrateError(
-                        "UNKNOWN".to_string(),
-                        CURRENT_VERSION.to_string(),
-                    ),
-                    msg: Some(format!(
-                        "Not support migrate {:?} from UNKNOWN to {}",
-                        self.path, CURRENT_VERSION
-                    )),
-                }),
-            },
+    /// Migrates the store file in place, after backing it up, and reports what was done. A store
+    /// already in the current layout is left as it is.
+    pub fn run(&self) -> Result<MigrationReport> {
+        let (kv, report) = self.migrate()?;
+        if report.is_migrated() && !self.dry_run {
+            kv.commit()?;
+        }
+        Ok(report)
+    }
+
+    /// Reads the store file and migrates it to the current layout in memory. The store file is
+    /// backed up if it is in an older layout, unless this is a dry run, but left to the caller
+    /// to rewrite.
+    pub(crate) fn migrate(&self) -> Result<(MicroKV, MigrationReport)> {
+        // a dry run writes nothing, not even the lock file
+        let lock = FileLock::shared(&self.path, LockMode::default(), self.dry_run)?;
+        let bytes = fs::read(&self.path)?;
+        drop(lock);
+
+        let mut store = self.load(&bytes)?;
+        let from = store.version().to_string();
+        let mut steps = Vec::new();
+        let migrators = std::iter::once(&self.legacy as &dyn Migrator)
+            .chain(self.migrators.iter().map(|migrator| migrator.as_ref()))
+            .collect::<Vec<_>>();
+        let mut kv = loop {
+            if let Store::V040(kv) = store {
+                break kv;
+            }
+            let version = store.version().to_string();
+            let migrator = migrators
+                .iter()
+                .rev()
+                .find(|migrator| migrator.source_version() == version);
+            // a chain longer than the registry goes round in circles
+            let migrator = match migrator {
+                Some(migrator) if steps.len() < migrators.len() => migrator,
+                _ => return Err(self.error(&version, "no migrator leads to the current layout")),
+            };
+            let (from, to) = (migrator.source_version(), migrator.target_version());
+            store = migrator
+                .migrate(store, self.pwd.as_ref())
+                .map_err(|e| self.step_error(from, to, e))?;
+            if store.version() != to {
+                return Err(self.step_error(
+                    from,
+                    to,
+                    KVError {
+                        error: ErrorType::Custom,
+                        msg: Some(format!("migrator returned a store of {}", store.version())),
+                    },
+                ));
+            }
+            steps.push((from.to_string(), to.to_string()));
+        };
+        kv.path = self.path.clone();
+
+        let (namespaces, entries) = count(&kv)?;
+        let mut report = MigrationReport {
+            path: self.path.clone(),
+            from,
+            to: kv.version().clone(),
+            steps,
+            namespaces,
+            entries,
+            backup: None,
+            dry_run: self.dry_run,
+        };
+        if report.is_migrated() && !self.dry_run {
+            report.backup = Some(self.backup(&bytes)?);
         }
+        Ok((kv, report))
     }
 
-    fn try_current(&self) -> Result<history::MicroKV030> {
-        helpers::read_file_and_deserialize_bincode(&self.path).map_err(|e| KVError {
-            error: ErrorType::MigrateError("0.3.0".to_string(), CURRENT_VERSION.to_string()),
-            msg: Some(format!("Failed to deserialize to 0.3.0 -> {:?}", e)),
-        })
+    /// Reads the store file into the store of its layout version, sniffed from its header, or
+    /// from its first bytes if it was written before the container format.
+    fn load(&self, bytes: &[u8]) -> Result<Store> {
+        // not being a store microkv can tell the layout of is not a migration error
+        let (version, store) = match format::split(bytes)? {
+            Some((header, body)) if header.store_version == "0.4.0" => (
+                header.store_version,
+                history::MicroKV040::decode(&self.path, body).map(Store::V040),
+            ),
+            Some((header, _)) => {
+                return Err(self.error(&header.store_version, "unknown layout version"));
+            }
+            None => match format::legacy_version(bytes) {
+                Some(version) if version == "0.3.0" => {
+                    let store = bincode::deserialize(bytes).map(Store::V030);
+                    (version, store.map_err(invalid))
+                }
+                Some(version) => return Err(self.error(&version, "unknown layout version")),
+                None => {
+                    let store = bincode::deserialize(bytes).map(Store::Less030);
+                    ("<0.3.0".to_string(), store.map_err(invalid))
+                }
+            },
+        };
+        store.map_err(|e| self.error(&version, &format!("cannot be read -> {:?}", e)))
+    }
+
+    /// Copies the store file, as it was read, next to it. An existing backup is never replaced:
+    /// backups taken within the same second are told apart by a counter.
+    fn backup(&self, bytes: &[u8]) -> Result<PathBuf> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let mut count = 0;
+        loop {
+            let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+            match count {
+                0 => name.push(format!(".{}.bak", secs)),
+                _ => name.push(format!(".{}.{}.bak", secs, count)),
+            }
+            let path = self.path.with_file_name(name);
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path);
+            match file {
+                Ok(mut file) => {
+                    file.write_all(bytes)?;
+                    file.sync_all()?;
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => count += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
 
-    fn try_less_than_030(&self) -> Result<MicroKV> {
-        Err(KVError {
-            error: ErrorType::MigrateError("<0.3.0".to_string(), CURRENT_VERSION.to_string()),
+    fn error(&self, from: &str, msg: &str) -> KVError {
+        KVError {
+            error: ErrorType::MigrateError(from.to_string(), CURRENT_VERSION.to_string()),
             msg: Some(format!(
-                "Not support migrate less than 0.3.0 to {}",
-                CURRENT_VERSION
+                "Cannot migrate {:?} from {} to {}: {}",
+                self.path, from, CURRENT_VERSION, msg
             )),
-        })
+        }
+    }
+
+    fn step_error(&self, from: &str, to: &str, e: KVError) -> KVError {
+        match e.error {
+            // the password given can't decrypt the values to migrate
+            ErrorType::InvalidPassword => e,
+            _ => KVError {
+                error: ErrorType::MigrateError(from.to_string(), to.to_string()),
+                msg: Some(format!(
+                    "Failed to migrate {:?} from {} to {} -> {:?}",
+                    self.path, from, to, e
+                )),
+            },
+        }
+    }
+}
+
+/// What the values of a store written before 0.3.0 were encoded from, which its layout does
+/// not record.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LegacyValues {
+    /// every value is a string, and migrating fails with `ErrorType::MigrateError` if one is not.
+    /// An empty string is encoded as eight zero bytes, as a zero `u64` or an empty sequence are,
+    /// so such a value fails too
+    #[default]
+    Strings,
+
+    /// like `Strings`, with eight zero bytes read as an empty string, for stores known to hold
+    /// strings only
+    StringsOrEmpty,
+
+    /// values are of any type, each kept as an array of its bincode bytes, to be read back as a
+    /// `Vec<u8>` and deserialized with its type
+    Bytes,
+}
+
+/// Migrates stores written before 0.3.0 to 0.3.0.
+///
+/// Before 0.3.0, a store held a single map of values, each encoded with bincode from its own
+/// type and sealed with the store-wide nonce under the SHA-256 of the password, if encrypted.
+/// Values are decrypted, converted to JSON as stated by `LegacyValues`, and sealed again under a
+/// fresh store-wide nonce into the default namespace of a 0.3.0 store, under the password if one
+/// is given.
+///
+/// The old layout records neither whether the store is encrypted nor the types of its values,
+/// so neither is guessed: every value is converted the same way, and one that isn't what was
+/// stated fails the migration rather than changing type.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Less030To030 {
+    /// whether the values are encrypted, `None` if only when a password is given
+    pub(crate) encrypted: Option<bool>,
+
+    /// what the values were encoded from
+    pub(crate) values: LegacyValues,
+}
+
+impl Migrator for Less030To030 {
+    fn source_version(&self) -> &str {
+        "<0.3.0"
+    }
+
+    fn target_version(&self) -> &str {
+        "0.3.0"
+    }
+
+    fn migrate(&self, store: Store, pwd: Option<&SecStr>) -> Result<Store> {
+        let mut old = match store {
+            Store::Less030(old) => old,
+            store => return Err(unexpected(&store, self.source_version())),
+        };
+        let pwd = match pwd {
+            Some(pwd) => Some(Kdf::Sha256.derive_key(pwd.unsecure())?),
+            None => None,
+        };
+        old.pwd = match self.encrypted.unwrap_or(pwd.is_some()) {
+            true if pwd.is_none() => {
+                return Err(KVError {
+                    error: ErrorType::InvalidPassword,
+                    msg: Some(
+                        "store written before 0.3.0 needs its password to be migrated".to_string(),
+                    ),
+                })
+            }
+            true => pwd.clone(),
+            false => None,
+        };
+        let values = old.lock_read(|data| {
+            data.iter()
+                .map(|(key, value)| Ok((key.clone(), old.open_value(value)?)))
+                .collect::<Result<Vec<(String, Vec<u8>)>>>()
+        })?;
+        let values = values.map_err(|_| KVError {
+            error: ErrorType::InvalidPassword,
+            msg: Some("cannot decrypt the values of a store written before 0.3.0".to_string()),
+        })?;
+
+        let storage = Arc::new(RwLock::new(HashMap::new()));
+        let kv = history::MicroKV030::create(
+            old.path.clone(),
+            pwd,
+            helpers::gen_nonce(),
+            old.is_auto_commit,
+            storage.clone(),
+        );
+        let mut data = LegacyKV::new();
+        for (key, value) in values {
+            let value = match self.values {
+                LegacyValues::Strings | LegacyValues::StringsOrEmpty => {
+                    match legacy_string(&value) {
+                        Some(string)
+                            if string.is_empty() && self.values == LegacyValues::Strings =>
+                        {
+                            return Err(empty_string(&key))
+                        }
+                        Some(string) => Value::String(string),
+                        None => return Err(not_string(&key)),
+                    }
+                }
+                LegacyValues::Bytes => Value::from(value),
+            };
+            data.insert(key, kv.encode_value(&value)?);
+        }
+        storage
+            .write()
+            .map_err(|_| KVError {
+                error: ErrorType::PoisonError,
+                msg: None,
+            })?
+            .insert("".to_string(), Arc::new(RwLock::new(data)));
+        Ok(Store::V030(kv))
+    }
+}
+
+/// Migrates stores written by 0.3.0 to 0.4.0.
+///
+/// 0.3.0 sealed every value with the store-wide nonce under the SHA-256 of the password, with a
+/// cipher that does not bind values to their entry. With the password, values are decrypted
+/// and sealed again, each under a fresh nonce, with the default cipher, under a new random
+/// data key held in an Argon2id key slot for the password.
+///
+/// Without it, values are moved over as they are, with the store-wide nonce stored alongside
+/// each, and the store keeps the legacy cipher, so that it can still be opened. An encrypted
+/// store is given a legacy key slot, so that it refuses reads and writes until it is unlocked
+/// with its password. It is then sealed again as above, unless the handle is read-only. Until
+/// then, its values are not bound to their entries, and share one nonce.
+pub struct V030To040;
+
+impl Migrator for V030To040 {
+    fn source_version(&self) -> &str {
+        "0.3.0"
+    }
+
+    fn target_version(&self) -> &str {
+        "0.4.0"
+    }
+
+    fn migrate(&self, store: Store, pwd: Option<&SecStr>) -> Result<Store> {
+        let mut old = match store {
+            Store::V030(old) => old,
+            store => return Err(unexpected(&store, self.source_version())),
+        };
+        let kv = match pwd {
+            Some(pwd) => {
+                old.pwd = Some(Kdf::Sha256.derive_key(pwd.unsecure())?);
+                let kv = MicroKV::create(
+                    old.path.clone(),
+                    Kdf::default(),
+                    None,
+                    old.is_auto_commit,
+                    Arc::new(RwLock::new(HashMap::new())),
+                );
+                kv.keyring_mut()?
+                    .unlock(&Credential::Password(pwd.clone()), None)?;
+                let storage = reseal030(&old, &kv)?;
+                *kv.storage.write().map_err(|_| KVError {
+                    error: ErrorType::PoisonError,
+                    msg: None,
+                })? = storage;
+                kv
+            }
+            None => {
+                // 0.3.0 hashed the password with a single SHA-256, and sealed values with
+                // secretbox. The store is kept encrypted with a legacy slot for the password, so
+                // that nothing is read or written until it is unlocked.
+                let kv = MicroKV::create(
+                    old.path.clone(),
+                    Kdf::Sha256,
+                    None,
+                    old.is_auto_commit,
+                    Arc::new(RwLock::new(move030(&old)?)),
+                );
+                if is_encrypted030(&old)? {
+                    let mut keyring = kv.keyring_mut()?;
+                    keyring.cipher = Cipher::XSalsa20Poly1305;
+                    keyring.slots = vec![KeySlot::legacy(SlotKind::Password(Kdf::Sha256))];
+                }
+                kv
+            }
+        };
+        Ok(Store::V040(kv))
+    }
+}
+
+/// Moves the values of a 0.3.0 store over as they are, with the store-wide nonce.
+fn move030(old: &history::MicroKV030) -> Result<HashMap<String, Storage>> {
+    let old_storage = old.storage.read().map_err(|_| KVError {
+        error: ErrorType::PoisonError,
+        msg: None,
+    })?;
+    let mut storage = HashMap::new();
+    for (namespace, old_kv) in old_storage.iter() {
+        let old_kv = old_kv.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let kv = old_kv
+            .iter()
+            .map(|(key, value)| {
+                let value = SealedValue::new(Some(old.nonce.0.to_vec()), value.clone());
+                (key.clone(), value)
+            })
+            .collect::<KV>();
+        storage.insert(namespace.clone(), Arc::new(RwLock::new(kv)));
+    }
+    Ok(storage)
+}
+
+/// Decrypts the values of a 0.3.0 store, and seals them again for `kv`, which is unlocked.
+fn reseal030(old: &history::MicroKV030, kv: &MicroKV) -> Result<HashMap<String, Storage>> {
+    let old_storage = old.storage.read().map_err(|_| KVError {
+        error: ErrorType::PoisonError,
+        msg: None,
+    })?;
+    let mut storage = HashMap::new();
+    for (namespace, old_kv) in old_storage.iter() {
+        let old_kv = old_kv.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let mut data = KV::new();
+        for (key, value) in old_kv.iter() {
+            let value = old.decode_value(value).map_err(|_| KVError {
+                error: ErrorType::InvalidPassword,
+                msg: Some("cannot decrypt the values of a store written by 0.3.0".to_string()),
+            })?;
+            data.insert(key.clone(), kv.encode_value(namespace, key, &value)?);
+        }
+        storage.insert(namespace.clone(), Arc::new(RwLock::new(data)));
+    }
+    Ok(storage)
+}
+
+/// Whether any value of a 0.3.0 store is sealed, which its layout does not record. Unencrypted
+/// values are JSON encoded with bincode, which no sealed value reads as.
+fn is_encrypted030(old: &history::MicroKV030) -> Result<bool> {
+    let storage = old.storage.read().map_err(|_| KVError {
+        error: ErrorType::PoisonError,
+        msg: None,
+    })?;
+    for data in storage.values() {
+        let data = data.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        if data.values().any(|value| old.decode_value(value).is_err()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Number of namespaces and entries of `kv`, as stored.
+fn count(kv: &MicroKV) -> Result<(usize, usize)> {
+    let storage = kv.storage.read().map_err(|_| KVError {
+        error: ErrorType::PoisonError,
+        msg: None,
+    })?;
+    let mut entries = 0;
+    for data in storage.values() {
+        let data = data.read().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        entries += data.len();
+    }
+    Ok((storage.len(), entries))
+}
+
+fn unexpected(store: &Store, version: &str) -> KVError {
+    KVError {
+        error: ErrorType::Custom,
+        msg: Some(format!(
+            "expected a store of {}, got {}",
+            version,
+            store.version()
+        )),
+    }
+}
+
+fn invalid(e: bincode::Error) -> KVError {
+    KVError {
+        error: ErrorType::FileError,
+        msg: Some(format!("{:?}", e)),
+    }
+}
+
+fn not_string(key: &str) -> KVError {
+    KVError {
+        error: ErrorType::Custom,
+        msg: Some(format!(
+            "value of {:?} is not a string: migrate with `LegacyValues::Bytes`, or with the \
+             password if the store is encrypted",
+            key
+        )),
+    }
+}
+
+fn empty_string(key: &str) -> KVError {
+    KVError {
+        error: ErrorType::Custom,
+        msg: Some(format!(
+            "value of {:?} may be an empty string or not a string: migrate with \
+             `LegacyValues::StringsOrEmpty` or `LegacyValues::Bytes`",
+            key
+        )),
+    }
+}
+
+/// Reads a value encoded with bincode before 0.3.0 as a string, if it is exactly one.
+fn legacy_string(bytes: &[u8]) -> Option<String> {
+    let string: String = bincode::deserialize(bytes).ok()?;
+    match bincode::serialized_size(&string).ok()? == bytes.len() as u64 {
+        true => Some(string),
+        false => None,
     }
 }
@@ -2,8 +2,11 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use crate::errors::{ErrorType, KVError, Result};
+use crate::history::NAMESPACE_ENTRY;
+use crate::keyring::Credential;
 use crate::kv::Value;
 use crate::types::KV;
+use crate::wal::LogEntry;
 use crate::MicroKV;
 
 #[derive(Clone)]
@@ -22,8 +25,77 @@ impl NamespaceMicroKV {
         }
     }
 
-    fn key(&self, key: impl AsRef<str>) -> String {
-        key.as_ref().to_string()
+    /// Name the namespace is stored under.
+    fn storage_namespace(&self) -> Result<String> {
+        self.microkv.storage_namespace(&self.namespace)
+    }
+
+    fn key(&self, key: impl AsRef<str>) -> Result<String> {
+        self.microkv.storage_key(&self.namespace, key)
+    }
+}
+
+/// A namespace of a store, as listed by `MicroKV::namespace_infos`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamespaceInfo {
+    pub name: String,
+
+    /// whether its values can't be read, as the store is locked, or the namespace has a key of
+    /// its own that was not given to this store
+    pub is_locked: bool,
+}
+
+impl NamespaceMicroKV {
+    /// Gives the namespace a key of its own, from a cleartext password, or unlocks it if it
+    /// already has one. Values of the namespace are then sealed under a separate data key,
+    /// which neither the store password nor the key of another namespace opens. Key names are
+    /// not covered by it, and stay visible to whoever holds the store password.
+    ///
+    /// Giving a namespace its first key seals its values again and commits the store, so the
+    /// store must be unlocked if it is encrypted. The namespace is unlocked for every handle
+    /// cloned from the same store. If the password does not unlock the namespace, every later
+    /// read and write of its values fails with `ErrorType::InvalidPassword`.
+    pub fn with_pwd_clear<S: AsRef<str>>(self, unsafe_pwd: S) -> Self {
+        self.with_credential(Credential::password(unsafe_pwd))
+    }
+
+    /// Like `with_pwd_clear`, with a hashed buffer.
+    pub fn with_pwd_hash(self, _pwd: [u8; 32]) -> Self {
+        self.with_credential(Credential::hash(_pwd))
+    }
+
+    /// Like `with_pwd_clear`, with any credential accepted by a key slot.
+    pub fn with_credential(self, credential: Credential) -> Self {
+        let _ = self.unlock(&credential);
+        self
+    }
+
+    /// Like `with_pwd_clear`, but fails with `ErrorType::InvalidPassword` straight away if the
+    /// password does not unlock the namespace.
+    pub fn try_with_pwd_clear<S: AsRef<str>>(self, unsafe_pwd: S) -> Result<Self> {
+        self.try_with_credential(Credential::password(unsafe_pwd))
+    }
+
+    /// Like `try_with_pwd_clear`, with any credential accepted by a key slot.
+    pub fn try_with_credential(self, credential: Credential) -> Result<Self> {
+        self.unlock(&credential)?;
+        Ok(self)
+    }
+
+    fn unlock(&self, credential: &Credential) -> Result<usize> {
+        let namespace = self.storage_namespace()?;
+        self.microkv.unlock_namespace(&namespace, credential)
+    }
+
+    /// Whether values of the namespace can't be read, see `NamespaceInfo::is_locked`.
+    pub fn is_locked(&self) -> bool {
+        match self.storage_namespace() {
+            Ok(namespace) => match self.microkv.keyring() {
+                Ok(keyring) => keyring.is_namespace_locked(&namespace),
+                Err(_) => true,
+            },
+            Err(_) => true,
+        }
     }
 }
 
@@ -65,15 +137,13 @@ impl NamespaceMicroKV {
     /// Decrypts and retrieves a value. Can return errors if lock is poisoned,
     /// ciphertext decryption doesn't work, and if parsing bytes fail.
     pub fn get(&self, key: impl AsRef<str>) -> Result<Option<Value>> {
-        let data_key = self.key(key);
-        let value = self.microkv.lock_read(&self.namespace, |kv| {
-            // initialize a copy of state
-            let data = kv.clone();
-
+        let namespace = self.storage_namespace()?;
+        let data_key = self.key(key)?;
+        let value = self.microkv.lock_read(&namespace, |kv| {
             // retrieve value from IndexMap if stored, decrypt and return
-            match data.get(&data_key) {
+            match kv.get(&data_key) {
                 Some(val) => {
-                    let v = match self.microkv.decode_value(val) {
+                    let v = match self.microkv.decode_value(&namespace, &data_key, val) {
                         Ok(v) => v,
                         Err(e) => return Err(e),
                     };
@@ -91,45 +161,70 @@ impl NamespaceMicroKV {
     where
         V: Serialize,
     {
-        let data_key = self.key(key);
-        self.microkv.lock_write(&self.namespace, |data: &mut KV| {
-            // to retain best-case constant runtime, we remove the key-value if found
-            if data.contains_key(&data_key) {
-                let _ = data.remove(&data_key).unwrap();
-            }
-
-            let value = match self.microkv.encode_value(value) {
+        let namespace = self.storage_namespace()?;
+        let data_key = self.key(&key)?;
+        let namespace_entry = self.microkv.namespace_entry(&namespace, &self.namespace)?;
+        let entries = self.microkv.lock_write(&namespace, |data: &mut KV| {
+            let mut value = match self.microkv.encode_value(&namespace, &data_key, value) {
                 Ok(v) => v,
                 Err(e) => return Err(e),
             };
-            data.insert(data_key.clone(), value);
-            Ok(())
+            self.microkv
+                .seal_name(&namespace, &data_key, key.as_ref(), &mut value)?;
+            self.microkv.mark_dirty(&namespace, &data_key, data)?;
+
+            // to retain best-case constant runtime, we remove the key-value if found
+            if let Some(old) = data.remove(&data_key) {
+                value.version = old.version;
+            }
+            value.version += 1;
+            data.insert(data_key.clone(), value.clone());
+            let mut entries = vec![LogEntry {
+                namespace: namespace.clone(),
+                key: data_key.clone(),
+                value: Some(value),
+            }];
+
+            // keep the original name of the namespace, if names are blinded
+            if let Some(ref entry) = namespace_entry {
+                if !data.contains_key(NAMESPACE_ENTRY) {
+                    self.microkv.mark_dirty(&namespace, NAMESPACE_ENTRY, data)?;
+                    data.insert(NAMESPACE_ENTRY.to_string(), entry.clone());
+                    entries.push(LogEntry {
+                        namespace: namespace.clone(),
+                        key: NAMESPACE_ENTRY.to_string(),
+                        value: Some(entry.clone()),
+                    });
+                }
+            }
+            Ok(entries)
         })??;
-        if !self.microkv.is_auto_commit {
-            return Ok(());
-        }
-        self.microkv.commit()
+        self.microkv.auto_commit(entries)
     }
 
     /// Delete removes an entry in the key value store.
     pub fn delete(&self, key: impl AsRef<str>) -> Result<()> {
-        let data_key = self.key(key);
-        self.microkv.lock_write(&self.namespace, |data| {
+        let namespace = self.storage_namespace()?;
+        let data_key = self.key(key)?;
+        self.microkv.lock_write(&namespace, |data| -> Result<()> {
+            self.microkv.mark_dirty(&namespace, &data_key, data)?;
             // delete entry from BTreeMap by key
             let _ = data.remove(&data_key);
-        })?;
-
-        if !self.microkv.is_auto_commit {
-            return Ok(());
-        }
-        self.microkv.commit()
+            Ok(())
+        })??;
+        self.microkv.auto_commit(vec![LogEntry {
+            namespace,
+            key: data_key,
+            value: None,
+        }])
     }
 
     /// Helper routine that acquires a reader lock and checks if a key exists.
     pub fn exists(&self, key: impl AsRef<str>) -> Result<bool> {
-        let data_key = self.key(key);
+        let namespace = self.storage_namespace()?;
+        let data_key = self.key(key)?;
         self.microkv
-            .lock_read(&self.namespace, |data| data.contains_key(&data_key))
+            .lock_read(&namespace, |data| data.contains_key(&data_key))
     }
 
     /// Safely consumes an iterator over the keys in the `IndexMap` and returns a
@@ -138,11 +233,10 @@ impl NamespaceMicroKV {
     /// Note that key iteration, not value iteration, is only supported in order to preserve
     /// security guarentees.
     pub fn keys(&self) -> Result<Vec<String>> {
-        let keys = self.microkv.lock_read(&self.namespace, |kv| {
-            // initialize a copy to data
-            let data = kv.clone();
-            data.keys().map(|x| x.to_string()).collect::<Vec<String>>()
-        })?;
+        let namespace = self.storage_namespace()?;
+        let keys = self
+            .microkv
+            .lock_read(&namespace, |kv| self.microkv.entry_names(&namespace, kv))??;
         Ok(keys)
     }
 
@@ -152,12 +246,8 @@ impl NamespaceMicroKV {
     /// Note that key iteration, not value iteration, is only supported in order to preserve
     /// security guarentees.
     pub fn sorted_keys(&self) -> Result<Vec<String>> {
-        let keys = self.microkv.lock_read(&self.namespace, |kv| {
-            // initialize a copy to data, and sort keys in-place
-            let mut data = kv.clone();
-            data.sort_keys();
-            data.keys().map(|x| x.to_string()).collect::<Vec<String>>()
-        })?;
+        let mut keys = self.keys()?;
+        keys.sort();
         Ok(keys)
     }
 
@@ -165,15 +255,26 @@ impl NamespaceMicroKV {
     /// not delete the persistent storage file from disk. The `IndexMap` remains,
     /// and its capacity is kept the same.
     pub fn clear(&self) -> Result<()> {
-        self.microkv.lock_write(&self.namespace, |data| {
+        let namespace = self.storage_namespace()?;
+        let names_blinded = self.microkv.blinds_names()?;
+        self.microkv.lock_write(&namespace, |data| -> Result<()> {
+            for key in data.keys() {
+                if !(names_blinded && key == NAMESPACE_ENTRY) {
+                    self.microkv.mark_dirty(&namespace, key, data)?;
+                }
+            }
+
             // first, iterate over the IndexMap and coerce drop on the secure value wrappers
-            for (_, value) in data.iter_mut() {
-                value.zero_out();
+            for (key, value) in data.iter_mut() {
+                if !(names_blinded && key == NAMESPACE_ENTRY) {
+                    value.zero_out();
+                }
             }
 
-            // next, clear all entries from the IndexMap
-            data.clear();
-        })?;
+            // next, clear all entries from the IndexMap, keeping the namespace name if blinded
+            data.retain(|key, _| names_blinded && key == NAMESPACE_ENTRY);
+            Ok(())
+        })??;
 
         // auto commit
         if !self.microkv.is_auto_commit {
@@ -0,0 +1,194 @@
+//! Defines the options a store is opened with. Opening a store only reads it: the store file is
+//! left as it is, unless it is in the layout of an older version and `MigrateMode::Auto` writes
+//! it back in the current one, or `OpenOptions::create` writes a new store. A store opened with
+//! `OpenOptions::read_only` is never written at all.
+
+use std::path::PathBuf;
+
+use secstr::{SecStr, SecVec};
+
+use crate::errors::{ErrorType, KVError, Result};
+use crate::helpers;
+use crate::keyring::Credential;
+use crate::migrate::{LegacyValues, Migrate};
+use crate::MicroKV;
+
+/// What opening a store in the layout of an older version does.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MigrateMode {
+    /// the store is backed up, migrated, and written back in the current layout
+    #[default]
+    Auto,
+
+    /// the store is backed up and migrated in memory, and the store file keeps its layout until
+    /// the handle commits
+    Explicit,
+
+    /// opening fails with `ErrorType::MigrateError`, leaving the store to `Migrate::run`
+    Never,
+}
+
+/// Options to open a store with, set with builder methods.
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    read_only: bool,
+    create: bool,
+    create_new: bool,
+    must_exist: bool,
+    migrate: MigrateMode,
+
+    /// whether the values of a store written before 0.3.0 are encrypted, `None` if only when a
+    /// password is given
+    legacy_encrypted: Option<bool>,
+
+    /// what the values of a store written before 0.3.0 were encoded from
+    legacy_values: LegacyValues,
+
+    /// cleartext password to unlock the store with
+    pwd: Option<SecStr>,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the store read-only: nothing is written, not even on open, and any change fails
+    /// with `ErrorType::ReadOnly`. The store must exist, and is only migrated in memory if it is
+    /// in an older layout.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Writes a new empty store right away if there is none. Otherwise, a missing store is
+    /// opened empty, and only written by its first commit.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Like `create`, but fails if the store already exists.
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Fails if the store does not exist, instead of opening it empty.
+    pub fn must_exist(mut self, must_exist: bool) -> Self {
+        self.must_exist = must_exist;
+        self
+    }
+
+    /// Sets what opening a store in the layout of an older version does.
+    pub fn migrate(mut self, mode: MigrateMode) -> Self {
+        self.migrate = mode;
+        self
+    }
+
+    /// Sets whether the values of a store written before 0.3.0 are encrypted, see
+    /// `Migrate::set_legacy_encrypted`.
+    pub fn legacy_encrypted(mut self, encrypted: bool) -> Self {
+        self.legacy_encrypted = Some(encrypted);
+        self
+    }
+
+    /// Sets what the values of a store written before 0.3.0 were encoded from, see
+    /// `Migrate::set_legacy_values`.
+    pub fn legacy_values(mut self, values: LegacyValues) -> Self {
+        self.legacy_values = values;
+        self
+    }
+
+    /// Unlocks the store with `unsafe_pwd` once it is opened, failing if the password does not
+    /// unlock it. Encrypted stores written before 0.3.0 need it to be migrated.
+    pub fn with_pwd_clear<S: AsRef<str>>(mut self, unsafe_pwd: S) -> Self {
+        self.pwd = Some(SecVec::new(unsafe_pwd.as_ref().as_bytes().to_vec()));
+        self
+    }
+}
+
+impl OpenOptions {
+    /// Opens the store `dbname` in the default workspace directory.
+    pub fn open<S: AsRef<str>>(&self, dbname: S) -> Result<MicroKV> {
+        let mut path = helpers::get_home_dir();
+        path.push(helpers::DEFAULT_WORKSPACE_PATH);
+        self.open_with_base_path(dbname, path)
+    }
+
+    /// Opens the store `dbname` in `base_path`.
+    pub fn open_with_base_path<S: AsRef<str>>(
+        &self,
+        dbname: S,
+        base_path: PathBuf,
+    ) -> Result<MicroKV> {
+        let path = helpers::get_db_path_with_base_path(dbname.as_ref(), base_path.clone());
+        if self.read_only && (self.create || self.create_new) {
+            return Err(KVError {
+                error: ErrorType::ReadOnly,
+                msg: Some(format!("store {:?} can't be created read-only", path)),
+            });
+        }
+        let exists = path.is_file();
+        if exists && self.create_new {
+            return Err(file_error(format!("store {:?} already exists", path)));
+        }
+        if !exists && (self.must_exist || self.read_only) {
+            return Err(file_error(format!("store {:?} does not exist", path)));
+        }
+
+        let (kv, write) = match exists {
+            true => self.read(path)?,
+            false => (
+                MicroKV::new_with_base_path(dbname, base_path),
+                self.create || self.create_new,
+            ),
+        };
+        let kv = match self.pwd {
+            Some(ref pwd) => kv.try_with_credential(Credential::Password(pwd.clone()))?,
+            None => kv,
+        };
+        if write {
+            kv.commit()?;
+        }
+        Ok(kv)
+    }
+
+    /// Reads the store at `path`, migrating it if it is in an older layout. Returns whether the
+    /// store must be written back.
+    fn read(&self, path: PathBuf) -> Result<(MicroKV, bool)> {
+        // nothing is backed up if nothing may be written
+        let dry_run = self.read_only || self.migrate == MigrateMode::Never;
+        let mut migrate = Migrate::new(path)
+            .set_dry_run(dry_run)
+            .set_legacy_values(self.legacy_values);
+        if let Some(encrypted) = self.legacy_encrypted {
+            migrate = migrate.set_legacy_encrypted(encrypted);
+        }
+        if let Some(ref pwd) = self.pwd {
+            migrate = migrate.with_pwd(pwd.clone());
+        }
+        let (mut kv, report) = migrate.migrate()?;
+        if report.is_migrated() && self.migrate == MigrateMode::Never {
+            return Err(KVError {
+                error: ErrorType::MigrateError(report.from.clone(), report.to.clone()),
+                msg: Some(format!(
+                    "store {:?} is in the layout of {}, and is not migrated on open",
+                    report.path, report.from
+                )),
+            });
+        }
+        kv.read_only = self.read_only;
+        // replays the write-ahead log, and checks the store
+        kv.reload()?;
+        let write = report.is_migrated() && self.migrate == MigrateMode::Auto && !self.read_only;
+        Ok((kv, write))
+    }
+}
+
+fn file_error(msg: String) -> KVError {
+    KVError {
+        error: ErrorType::FileError,
+        msg: Some(msg),
+    }
+}
@@ -0,0 +1,129 @@
+//! Defines where the credential that unlocks a store is read from. A `KeyProvider` is handed to
+//! `MicroKV::with_key_provider`, which reads the credential once and unlocks the store with it.
+//!
+//! Built-in providers read an environment variable, a keyfile or a password typed at the
+//! terminal. Any closure returning a `Credential` is a provider as well, which covers other
+//! sources such as a secrets manager or a key agent.
+//!
+//! A `KeyWrapper` goes one step further, and never hands the key out: the data key of the
+//! store is wrapped and unwrapped by an external service, e.g. a KMS or an HSM, and the store
+//! only keeps the wrapped copy in a key slot. See `Credential::wrapper`.
+//!
+//! ## Example
+//!
+//! ```rust
+//! use microkv::provider::EnvProvider;
+//! use microkv::MicroKV;
+//!
+//! std::env::set_var("MICROKV_PASSWORD", "p@ssw0rd");
+//! let kv: MicroKV = MicroKV::new("example").with_key_provider(&EnvProvider::new("MICROKV_PASSWORD"));
+//! ```
+
+use std::env;
+use std::path::PathBuf;
+
+use secstr::SecStr;
+
+use crate::errors::{ErrorType, KVError, Result};
+use crate::keyring::Credential;
+
+/// A source of the credential that unlocks a store.
+pub trait KeyProvider {
+    /// Reads the credential.
+    fn credential(&self) -> Result<Credential>;
+}
+
+impl<F> KeyProvider for F
+where
+    F: Fn() -> Result<Credential>,
+{
+    fn credential(&self) -> Result<Credential> {
+        self()
+    }
+}
+
+/// Wraps the data key of a store with a key held outside of it, e.g. by a KMS. Failures should
+/// be reported as `ErrorType::ProviderError`.
+pub trait KeyWrapper: Send + Sync {
+    /// Identifies the wrapping key, e.g. a KMS key ARN. It is recorded in the key slot, so that
+    /// a slot is only handed to the wrapper it was sealed by.
+    fn id(&self) -> String;
+
+    /// Wraps `data_key`, returning an opaque blob kept in the key slot.
+    fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>>;
+
+    /// Unwraps a blob returned by `wrap`.
+    fn unwrap(&self, wrapped: &[u8]) -> Result<SecStr>;
+}
+
+/// Reads a cleartext password from an environment variable.
+pub struct EnvProvider {
+    var: String,
+}
+
+impl EnvProvider {
+    pub fn new<S: AsRef<str>>(var: S) -> Self {
+        Self {
+            var: var.as_ref().to_string(),
+        }
+    }
+}
+
+impl KeyProvider for EnvProvider {
+    fn credential(&self) -> Result<Credential> {
+        let pwd = env::var(&self.var).map_err(|e| KVError {
+            error: ErrorType::ProviderError,
+            msg: Some(format!("cannot read password from `{}`: {}", self.var, e)),
+        })?;
+        Ok(Credential::password(pwd))
+    }
+}
+
+/// Reads the contents of a keyfile.
+pub struct KeyfileProvider {
+    path: PathBuf,
+}
+
+impl KeyfileProvider {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl KeyProvider for KeyfileProvider {
+    fn credential(&self) -> Result<Credential> {
+        Credential::keyfile(&self.path).map_err(|e| KVError {
+            error: ErrorType::ProviderError,
+            msg: Some(format!("cannot read keyfile {:?}: {}", self.path, e)),
+        })
+    }
+}
+
+/// Prompts for a cleartext password on the terminal, without echoing it.
+pub struct PromptProvider {
+    prompt: String,
+}
+
+impl PromptProvider {
+    pub fn new<S: AsRef<str>>(prompt: S) -> Self {
+        Self {
+            prompt: prompt.as_ref().to_string(),
+        }
+    }
+}
+
+impl Default for PromptProvider {
+    fn default() -> Self {
+        Self::new("Password: ")
+    }
+}
+
+impl KeyProvider for PromptProvider {
+    fn credential(&self) -> Result<Credential> {
+        let pwd = rpassword::read_password_from_tty(Some(&self.prompt)).map_err(|e| KVError {
+            error: ErrorType::ProviderError,
+            msg: Some(format!("cannot read password from the terminal: {}", e)),
+        })?;
+        Ok(Credential::password(pwd))
+    }
+}
@@ -0,0 +1,295 @@
+//! Defines transactions, which change several entries, across namespaces, all at once, e.g. both
+//! halves of a credential pair. Writes made in a transaction are buffered, and only seen by the
+//! transaction itself until it ends. If its closure returns an error, nothing is applied.
+//! Otherwise, every value is sealed first, then every write is applied while the storage map of
+//! the store is locked, so that readers see either none of the writes or all of them.
+//!
+//! With auto-commit, the writes are then persisted together, as a single record of the
+//! write-ahead log or in a single commit, which replaces the store file at once, before the
+//! storage map is unlocked. If that fails, they are rolled back in memory too, and no reader
+//! has seen them. Without auto-commit, they are persisted by the next commit, like any other
+//! write.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use indexmap::IndexMap;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::{ErrorType, KVError, Result};
+use crate::history::NAMESPACE_ENTRY;
+use crate::kv::Value;
+use crate::types::{SealedValue, Storage, KV};
+use crate::wal::LogEntry;
+use crate::MicroKV;
+
+/// Writes of a transaction, as given to the closure of `MicroKV::transaction`.
+pub struct Transaction<'a> {
+    microkv: &'a MicroKV,
+
+    /// values written by namespace and key, in order, `None` if deleted
+    writes: RefCell<IndexMap<(String, String), Option<Value>>>,
+}
+
+/// A namespace as seen by a transaction.
+pub struct TransactionNamespace<'a> {
+    namespace: String,
+    transaction: &'a Transaction<'a>,
+}
+
+/// Write sealed for the entry it is stored under, ready to be applied.
+struct SealedWrite {
+    namespace: String,
+    key: String,
+    value: Option<SealedValue>,
+
+    /// whether it is only written if the entry does not exist, as for the name of a namespace
+    if_absent: bool,
+}
+
+/// Value an entry had before a transaction applied its write, to roll it back.
+struct Undo {
+    namespace: String,
+    key: String,
+    value: Option<SealedValue>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(microkv: &'a MicroKV) -> Self {
+        Self {
+            microkv,
+            writes: RefCell::new(IndexMap::new()),
+        }
+    }
+
+    pub fn namespace(&'a self, namespace: impl AsRef<str>) -> TransactionNamespace<'a> {
+        TransactionNamespace {
+            namespace: namespace.as_ref().to_string(),
+            transaction: self,
+        }
+    }
+
+    pub fn namespace_default(&'a self) -> TransactionNamespace<'a> {
+        self.namespace("")
+    }
+}
+
+impl<'a> TransactionNamespace<'a> {
+    pub fn get_as<V>(&self, key: impl AsRef<str>) -> Result<Option<V>>
+    where
+        V: DeserializeOwned + 'static,
+    {
+        match self.get(key)? {
+            Some(v) => Ok(Some(serde_json::from_value(v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieves a value, as written by the transaction if it wrote it, or else as it is in the
+    /// store.
+    pub fn get(&self, key: impl AsRef<str>) -> Result<Option<Value>> {
+        let write = (self.namespace.clone(), key.as_ref().to_string());
+        if let Some(value) = self.transaction.writes.borrow().get(&write) {
+            return Ok(value.clone());
+        }
+        self.transaction.microkv.namespace(&self.namespace).get(key)
+    }
+
+    /// Whether a key exists, once the writes of the transaction so far are applied.
+    pub fn exists(&self, key: impl AsRef<str>) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Adds a key-value pair once the transaction ends.
+    pub fn put<V>(&self, key: impl AsRef<str>, value: &V) -> Result<()>
+    where
+        V: Serialize,
+    {
+        let value = serde_json::to_value(value)?;
+        self.write(key, Some(value));
+        Ok(())
+    }
+
+    /// Removes an entry once the transaction ends.
+    pub fn delete(&self, key: impl AsRef<str>) -> Result<()> {
+        self.write(key, None);
+        Ok(())
+    }
+
+    fn write(&self, key: impl AsRef<str>, value: Option<Value>) {
+        let write = (self.namespace.clone(), key.as_ref().to_string());
+        self.transaction.writes.borrow_mut().insert(write, value);
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// Applies the writes of the transaction to the store, and persists them with auto-commit.
+    pub(crate) fn apply(self) -> Result<()> {
+        let writes = self.writes.into_inner();
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let microkv = self.microkv;
+        microkv.ensure_writable()?;
+        microkv.keyring()?.ensure_unlocked()?;
+
+        // sealed first, so that nothing is applied unless every value could be sealed
+        let mut sealed = Vec::with_capacity(writes.len());
+        let mut namespace_entries = HashMap::new();
+        for ((namespace, key), value) in writes.iter() {
+            let storage_namespace = microkv.storage_namespace(namespace)?;
+            let storage_key = microkv.storage_key(namespace, key)?;
+            let value = match value {
+                Some(value) => {
+                    let mut value =
+                        microkv.encode_value(&storage_namespace, &storage_key, value)?;
+                    microkv.seal_name(&storage_namespace, &storage_key, key, &mut value)?;
+                    if !namespace_entries.contains_key(&storage_namespace) {
+                        let entry = microkv.namespace_entry(&storage_namespace, namespace)?;
+                        namespace_entries.insert(storage_namespace.clone(), entry);
+                    }
+                    Some(value)
+                }
+                None => None,
+            };
+            sealed.push(SealedWrite {
+                namespace: storage_namespace,
+                key: storage_key,
+                value,
+                if_absent: false,
+            });
+        }
+        // keep the original name of each namespace written to, if names are blinded
+        for (namespace, entry) in namespace_entries {
+            if let Some(entry) = entry {
+                sealed.push(SealedWrite {
+                    namespace,
+                    key: NAMESPACE_ENTRY.to_string(),
+                    value: Some(entry),
+                    if_absent: true,
+                });
+            }
+        }
+
+        microkv.reload()?;
+        let dirty = microkv.dirty_mut()?.clone();
+        let mut undo = Vec::new();
+        let mut created = Vec::new();
+        let mut storage_map = microkv.storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        let applied = apply_writes(microkv, &mut storage_map, sealed, &mut undo, &mut created);
+        let entries = match applied {
+            Ok(entries) => entries,
+            Err(e) => {
+                roll_back(&mut storage_map, undo, created)?;
+                *microkv.dirty_mut()? = dirty;
+                return Err(e);
+            }
+        };
+
+        // persisted with the storage map still locked, so that no reader or writer gets to the
+        // entries before they are either persisted or rolled back
+        match microkv.auto_commit_locked(entries, &mut storage_map) {
+            Ok(conflicts) => {
+                drop(storage_map);
+                microkv.report_conflicts(&conflicts);
+                Ok(())
+            }
+            Err(e) => {
+                roll_back(&mut storage_map, undo, created)?;
+                *microkv.dirty_mut()? = dirty;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Applies `writes` to `storage_map`, recording what they replaced in `undo` and the
+/// namespaces they added in `created`. Returns the entries to persist.
+fn apply_writes(
+    microkv: &MicroKV,
+    storage_map: &mut HashMap<String, Storage>,
+    writes: Vec<SealedWrite>,
+    undo: &mut Vec<Undo>,
+    created: &mut Vec<String>,
+) -> Result<Vec<LogEntry>> {
+    let mut entries = Vec::with_capacity(writes.len());
+    for write in writes {
+        let SealedWrite {
+            namespace,
+            key,
+            value,
+            if_absent,
+        } = write;
+        let storage = match storage_map.get(&namespace) {
+            Some(storage) => storage.clone(),
+            // nothing to delete
+            None if value.is_none() => continue,
+            None => {
+                let storage = Arc::new(RwLock::new(KV::new()));
+                storage_map.insert(namespace.clone(), storage.clone());
+                created.push(namespace.clone());
+                storage
+            }
+        };
+        let mut data = storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        if if_absent && data.contains_key(&key) {
+            continue;
+        }
+        microkv.mark_dirty(&namespace, &key, &data)?;
+        let old = data.remove(&key);
+        let value = value.map(|mut value| {
+            value.version = old.as_ref().map(|old| old.version).unwrap_or(0) + 1;
+            data.insert(key.clone(), value.clone());
+            value
+        });
+        undo.push(Undo {
+            namespace: namespace.clone(),
+            key: key.clone(),
+            value: old,
+        });
+        entries.push(LogEntry {
+            namespace,
+            key,
+            value,
+        });
+    }
+    Ok(entries)
+}
+
+/// Puts back the values recorded in `undo`, and removes the namespaces in `created`.
+fn roll_back(
+    storage_map: &mut HashMap<String, Storage>,
+    undo: Vec<Undo>,
+    created: Vec<String>,
+) -> Result<()> {
+    for undo in undo.into_iter().rev() {
+        let storage = match storage_map.get(&undo.namespace) {
+            Some(storage) => storage,
+            None => continue,
+        };
+        let mut data = storage.write().map_err(|_| KVError {
+            error: ErrorType::PoisonError,
+            msg: None,
+        })?;
+        match undo.value {
+            Some(value) => {
+                data.insert(undo.key, value);
+            }
+            None => {
+                let _ = data.remove(&undo.key);
+            }
+        }
+    }
+    for namespace in created {
+        let _ = storage_map.remove(&namespace);
+    }
+    Ok(())
+}
@@ -2,9 +2,118 @@ use std::sync::{Arc, RwLock};
 
 use indexmap::IndexMap;
 use secstr::SecVec;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::backend;
+use crate::encoding::Encoding;
+
+/// Defines a fixed-size array of public bytes, serialized as a byte string like the
+/// `sodiumoxide` types stores used to be written with, whichever crypto backend is used.
+macro_rules! public_bytes {
+    ($(#[$meta:meta])* $name:ident, $size:expr) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub struct $name(pub [u8; $size]);
+
+        impl $name {
+            /// Generates random bytes.
+            pub fn generate() -> Self {
+                Self::from_slice(&backend::randombytes($size)).unwrap()
+            }
+
+            /// Copies `bytes`, `None` if they are not of the right size.
+            pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+                if bytes.len() != $size {
+                    return None;
+                }
+                let mut array = [0; $size];
+                array.copy_from_slice(bytes);
+                Some($name(array))
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(&self.0)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                let bytes = Vec::<u8>::deserialize(deserializer)?;
+                Self::from_slice(&bytes).ok_or_else(|| {
+                    D::Error::invalid_length(bytes.len(), &concat!(stringify!($size), " bytes"))
+                })
+            }
+        }
+    };
+}
+
+public_bytes!(
+    /// Nonce of the XSalsa20-Poly1305 `secretbox`, shared by every value of a store written
+    /// before 0.4.0.
+    Nonce,
+    backend::XSALSA20POLY1305_NONCE_BYTES
+);
+
+public_bytes!(
+    /// Random salt of an Argon2id key derivation.
+    Salt,
+    backend::ARGON2ID_SALT_BYTES
+);
+
+/// A single value as it is kept in storage. Every encrypted value carries the public nonce it
+/// was sealed with, so no two values ever share a nonce under the same key.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedValue {
+    /// pseudorandom nonce that can be publicly known, of the size taken by the cipher of the
+    /// store. `None` if the value is not encrypted
+    pub nonce: Option<Vec<u8>>,
+
+    /// serialized value, sealed with AEAD if a password is set
+    pub data: SecVec<u8>,
+
+    /// original key name, sealed like a value under the names key if the store blinds names
+    pub name: Option<Box<SealedValue>>,
+
+    /// how the value was compressed and padded before it was sealed
+    pub encoding: Encoding,
+
+    /// number of puts made to the entry, so that reloads can tell whether another handle
+    /// changed it
+    pub version: u64,
+}
+
+impl SealedValue {
+    pub fn new(nonce: Option<Vec<u8>>, data: SecVec<u8>) -> Self {
+        Self {
+            nonce,
+            data,
+            name: None,
+            encoding: Encoding::default(),
+            version: 0,
+        }
+    }
+
+    /// Securely wipes the underlying value from memory.
+    pub fn zero_out(&mut self) {
+        self.data.zero_out()
+    }
+}
 
 /// An alias to a base data structure that supports storing
 /// associated types. An `IndexMap` is a strong choice due to
 /// strong asymptotic performance with sorted key iteration.
-pub type KV = IndexMap<String, SecVec<u8>>;
+pub type KV = IndexMap<String, SealedValue>;
 pub type Storage = Arc<RwLock<KV>>;
+
+/// The storage layout used before 0.4.0, where every value was sealed
+/// with the single nonce held by the store.
+pub type LegacyKV = IndexMap<String, SecVec<u8>>;
+pub type LegacyStorage = Arc<RwLock<LegacyKV>>;
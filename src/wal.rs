@@ -0,0 +1,151 @@
+//! Defines the write-ahead log of a store. With `MicroKV::set_write_ahead_log`, auto-commits of
+//! `put` and `delete` append a record of the entries they changed to a `<store>.wal` file next
+//! to the store, instead of rewriting the whole store. Opening or reloading the store replays
+//! the log onto the store file, its checkpoint. Any commit of the whole store, e.g. through
+//! `MicroKV::compact`, writes a new checkpoint and starts an empty log, which also happens on its
+//! own once the log outgrows the checkpoint.
+//!
+//! The log starts with the generation and MAC of the checkpoint it extends, and each record
+//! takes the next generation, with a MAC chained to the one before, see `crate::integrity`. A
+//! log left behind by a checkpoint that already holds its records is ignored, and a record torn
+//! by a crash while it was appended is dropped.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Result;
+use crate::helpers;
+use crate::types::SealedValue;
+
+/// Size the log must reach before it is compacted on its own, once it outgrows the checkpoint.
+pub(crate) const COMPACTION_THRESHOLD: u64 = 64 * 1024;
+
+/// First frame of the log, naming the checkpoint it extends.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LogHeader {
+    pub(crate) id: Vec<u8>,
+    pub(crate) generation: u64,
+    pub(crate) mac: Option<Vec<u8>>,
+}
+
+/// Change to a single entry, as stored: `None` if it was deleted.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    pub(crate) namespace: String,
+    pub(crate) key: String,
+    pub(crate) value: Option<SealedValue>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LogRecord {
+    pub(crate) generation: u64,
+    pub(crate) entries: Vec<LogEntry>,
+
+    /// MAC chained to the record before, `None` if the store is unencrypted
+    pub(crate) mac: Option<Vec<u8>>,
+}
+
+impl LogRecord {
+    /// Bytes the MAC of a record authenticates, bound to the store `id`.
+    pub(crate) fn authenticated(id: &[u8], generation: u64, entries: &[LogEntry]) -> Vec<u8> {
+        bincode::serialize(&(id, generation, entries)).unwrap()
+    }
+}
+
+pub(crate) struct Log {
+    pub(crate) header: LogHeader,
+    pub(crate) records: Vec<LogRecord>,
+
+    /// length of the log up to the end of its last whole record
+    len: u64,
+}
+
+impl Log {
+    /// Generation and MAC of the last record, or of the checkpoint if there is none.
+    pub(crate) fn head(&self) -> (u64, Option<Vec<u8>>) {
+        match self.records.last() {
+            Some(record) => (record.generation, record.mac.clone()),
+            None => (self.header.generation, self.header.mac.clone()),
+        }
+    }
+}
+
+/// Path of the write-ahead log of the store at `path`.
+pub(crate) fn log_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".wal");
+    path.with_file_name(name)
+}
+
+/// Reads the log of the store at `path`, `None` if there is none.
+pub(crate) fn read(path: &Path) -> Result<Option<Log>> {
+    let bytes = match fs::read(log_path(path)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut offset = 0;
+    let header: LogHeader = match next_frame(&bytes, &mut offset) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let mut records = Vec::new();
+    while let Some(record) = next_frame(&bytes, &mut offset) {
+        records.push(record);
+    }
+    Ok(Some(Log {
+        header,
+        records,
+        len: offset as u64,
+    }))
+}
+
+/// Deserializes the frame at `offset`, and moves past it. `None` at the end of the log, or if
+/// the frame was torn.
+fn next_frame<T: DeserializeOwned>(bytes: &[u8], offset: &mut usize) -> Option<T> {
+    let rest = &bytes[*offset..];
+    if rest.len() < 4 {
+        return None;
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&rest[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    let frame = rest.get(4..4 + len)?;
+    let value = bincode::deserialize(frame).ok()?;
+    *offset += 4 + len;
+    Some(value)
+}
+
+fn write_frame<T: Serialize>(file: &mut File, value: &T) -> Result<()> {
+    let frame = bincode::serialize(value).unwrap();
+    file.write_all(&(frame.len() as u32).to_le_bytes())?;
+    file.write_all(&frame)?;
+    Ok(())
+}
+
+/// Starts a new log for the store at `path`, extending the checkpoint named by `header`, and
+/// syncs it to disk.
+pub(crate) fn create(path: &Path, header: &LogHeader) -> Result<()> {
+    let log_path = log_path(path);
+    let mut file = File::create(&log_path)?;
+    write_frame(&mut file, header)?;
+    file.sync_all()?;
+    if let Some(dir) = log_path.parent() {
+        helpers::sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Appends `record` to `log`, the log of the store at `path` as last read, cutting off any
+/// torn record first. The log is synced to disk before its new length is returned.
+pub(crate) fn append(path: &Path, log: &Log, record: &LogRecord) -> Result<u64> {
+    let mut file = OpenOptions::new().append(true).open(log_path(path))?;
+    file.set_len(log.len)?;
+    write_frame(&mut file, record)?;
+    file.sync_all()?;
+    Ok(file.metadata()?.len())
+}
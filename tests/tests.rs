@@ -3,12 +3,33 @@
 //! Defines unit tests for:
 //! - simple database interactions
 //! - concurrent database interactions
+//! - migration of older stores
 
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use std::{env, thread};
 
+use fs2::FileExt;
+use secstr::{SecStr, SecVec};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use microkv::MicroKV;
+use microkv::cipher::Cipher;
+use microkv::dropbox;
+use microkv::encoding::{Compression, Encoding, Padding};
+use microkv::errors::ErrorType;
+use microkv::format;
+use microkv::history::{MicroKV030, MicroKVLess030};
+use microkv::kdf::Kdf;
+use microkv::keyring::{Credential, SlotKind};
+use microkv::lock::LockMode;
+use microkv::merge::{Conflict, ConflictPolicy};
+use microkv::migrate::{LegacyValues, Migrate};
+use microkv::options::{MigrateMode, OpenOptions};
+use microkv::provider::{EnvProvider, KeyWrapper};
+use microkv::types::{LegacyKV, Salt, SealedValue};
+use microkv::{helpers, MicroKV};
 
 // constants used throughout each test case
 static KEY_NAME: &str = "some_key";
@@ -174,3 +195,1505 @@ fn test_namespace_with_base_path_and_store() {
     assert!(keys_df1.contains(&"egg".to_string()));
     assert_eq!(keys_ns_one, vec!["zoo"]);
 }
+
+#[test]
+fn test_fresh_nonce_per_value() {
+    let kv: MicroKV = MicroKV::new("test_fresh_nonce_per_value").with_pwd_clear(TEST_PASSWORD);
+
+    // identical values must still be sealed under different nonces
+    kv.put("first", &"same value".to_string()).unwrap();
+    kv.put("second", &"same value".to_string()).unwrap();
+
+    let (first, second) = kv
+        .lock_read("", |data| {
+            (data["first"].nonce.clone(), data["second"].nonce.clone())
+        })
+        .unwrap();
+    assert!(first.is_some());
+    assert_ne!(first, second);
+}
+
+#[test]
+fn test_open_030_store() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_open_030_store", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    // write a store the way 0.3.0 did, with one nonce for every value
+    let pwd = SecVec::new(Sha256::digest(TEST_PASSWORD.as_bytes()).to_vec());
+    let storage = Arc::new(RwLock::new(HashMap::new()));
+    let nonce = helpers::gen_nonce();
+    let old = MicroKV030::create(path.clone(), Some(pwd), nonce, false, storage.clone());
+    let mut data = LegacyKV::new();
+    data.insert(
+        KEY_NAME.to_string(),
+        old.encode_value(&"old value".to_string()).unwrap(),
+    );
+    data.insert(
+        "other".to_string(),
+        old.encode_value(&"other value".to_string()).unwrap(),
+    );
+    storage
+        .write()
+        .unwrap()
+        .insert("".to_string(), Arc::new(RwLock::new(data)));
+    old.commit().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+
+    // without its password, the store keeps the legacy cipher until it is unlocked
+    let kv: MicroKV = MicroKV::open_with_base_path("test_open_030_store", dir.clone())
+        .expect("Failed to migrate 0.3.0 store");
+    assert!(kv.cipher().unwrap().is_legacy());
+
+    // it is still encrypted, and refuses reads and writes until it is unlocked
+    assert!(kv.is_locked());
+    let res = kv.get_as::<String>(KEY_NAME);
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::InvalidPassword
+    ));
+    let res = kv.put("planted", &"plaintext".to_string());
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::InvalidPassword
+    ));
+    kv.commit().unwrap();
+    let kv: MicroKV = MicroKV::open_with_base_path("test_open_030_store", dir.clone()).unwrap();
+    assert!(kv.is_locked());
+    let kv = kv.try_with_pwd_clear(TEST_PASSWORD).unwrap();
+    assert_eq!(kv.cipher().unwrap(), Cipher::default());
+    assert!(!kv.kdf().unwrap().is_legacy());
+    let res: String = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    assert_eq!(res, "old value");
+    let header = format::read_header(&path).unwrap().unwrap();
+    assert_eq!(header.cipher, Cipher::default());
+
+    // each value has a nonce of its own, instead of the store-wide one
+    let nonces = kv
+        .lock_read("", |data| {
+            (data[KEY_NAME].nonce.clone(), data["other"].nonce.clone())
+        })
+        .unwrap();
+    assert_ne!(nonces.0, nonces.1);
+    assert_ne!(nonces.0.as_deref(), Some(&nonce.0[..]));
+    assert_ne!(nonces.1.as_deref(), Some(&nonce.0[..]));
+
+    // with its password, it is sealed again as it is migrated
+    std::fs::write(&path, &bytes).unwrap();
+    let kv: MicroKV =
+        MicroKV::open_with_base_path_and_pwd("test_open_030_store", dir.clone(), TEST_PASSWORD)
+            .expect("Failed to migrate 0.3.0 store");
+    assert_eq!(kv.cipher().unwrap(), Cipher::default());
+    let header = format::read_header(&path).unwrap().unwrap();
+    assert_eq!(header.cipher, Cipher::default());
+    let kv: MicroKV = MicroKV::open_with_base_path("test_open_030_store", dir)
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    let res: String = kv.get_as_unwrap("other").expect("cannot retrieve value");
+    assert_eq!(res, "other value");
+    let resealed = kv
+        .lock_read("", |data| data["other"].nonce.clone())
+        .unwrap();
+    assert_ne!(resealed.as_deref(), Some(&nonce.0[..]));
+}
+
+#[test]
+fn test_open_less_030_store() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_open_less_030_store", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    // write a store the way 0.2.x did, with a single map of values
+    let pwd = SecVec::new(Sha256::digest(TEST_PASSWORD.as_bytes()).to_vec());
+    let storage = Arc::new(RwLock::new(LegacyKV::new()));
+    let old = MicroKVLess030::create(
+        path,
+        Some(pwd),
+        helpers::gen_nonce(),
+        false,
+        storage.clone(),
+    );
+    let value = TestStruct {
+        id: 1,
+        name: "old struct".to_string(),
+    };
+    {
+        let mut data = storage.write().unwrap();
+        data.insert(
+            KEY_NAME.to_string(),
+            old.encode_value(&"old value".to_string()).unwrap(),
+        );
+        data.insert("struct".to_string(), old.encode_value(&value).unwrap());
+    }
+    old.commit().unwrap();
+
+    // without the password, the ciphertexts are not taken for values
+    let res = MicroKV::open_with_base_path("test_open_less_030_store", dir.clone());
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::MigrateError(_, _)
+    ));
+    let res = OpenOptions::new()
+        .legacy_encrypted(true)
+        .open_with_base_path("test_open_less_030_store", dir.clone());
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::InvalidPassword
+    ));
+    let res = MicroKV::open_with_base_path_and_pwd("test_open_less_030_store", dir.clone(), "x");
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::InvalidPassword
+    ));
+
+    // the struct is not a string, and is not turned into one
+    let res = MicroKV::open_with_base_path_and_pwd(
+        "test_open_less_030_store",
+        dir.clone(),
+        TEST_PASSWORD,
+    );
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::MigrateError(_, _)
+    ));
+
+    let kv: MicroKV = OpenOptions::new()
+        .legacy_values(LegacyValues::Bytes)
+        .with_pwd_clear(TEST_PASSWORD)
+        .open_with_base_path("test_open_less_030_store", dir.clone())
+        .expect("Failed to migrate <0.3.0 store");
+    let bytes: Vec<u8> = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    let res: String = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(res, "old value");
+    let bytes: Vec<u8> = kv.get_as_unwrap("struct").expect("cannot retrieve value");
+    let res: TestStruct = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(res.name, value.name);
+
+    // the store was written in the current layout
+    let kv: MicroKV = MicroKV::open_with_base_path("test_open_less_030_store", dir)
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    let bytes: Vec<u8> = kv.get_as_unwrap("struct").expect("cannot retrieve value");
+    let res: TestStruct = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(res.id, value.id);
+}
+
+#[test]
+fn test_migrate_less_030_values() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_migrate_less_030_values", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    // an unencrypted store written by 0.2.x, holding a u64 rather than a string
+    let storage = Arc::new(RwLock::new(LegacyKV::new()));
+    let old = MicroKVLess030::create(
+        path.clone(),
+        None,
+        helpers::gen_nonce(),
+        false,
+        storage.clone(),
+    );
+    let value = old.encode_value(&42u64).unwrap();
+    storage.write().unwrap().insert(KEY_NAME.to_string(), value);
+    old.commit().unwrap();
+
+    let res = Migrate::new(path.clone()).set_dry_run(true).run();
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::MigrateError(_, _)
+    ));
+
+    Migrate::new(path.clone())
+        .set_legacy_encrypted(false)
+        .set_legacy_values(LegacyValues::Bytes)
+        .run()
+        .expect("Failed to migrate <0.3.0 store");
+    let kv: MicroKV =
+        MicroKV::open_with_base_path("test_migrate_less_030_values", dir.clone()).unwrap();
+    let bytes: Vec<u8> = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    let res: u64 = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(res, 42);
+
+    // a zero u64 is encoded as an empty string is, and only read as one if stated
+    let _ = std::fs::remove_file(&path);
+    let storage = Arc::new(RwLock::new(LegacyKV::new()));
+    let old = MicroKVLess030::create(
+        path.clone(),
+        None,
+        helpers::gen_nonce(),
+        false,
+        storage.clone(),
+    );
+    let value = old.encode_value(&0u64).unwrap();
+    storage.write().unwrap().insert(KEY_NAME.to_string(), value);
+    old.commit().unwrap();
+
+    let res = Migrate::new(path.clone())
+        .set_legacy_encrypted(false)
+        .set_dry_run(true)
+        .run();
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::MigrateError(_, _)
+    ));
+
+    Migrate::new(path)
+        .set_legacy_encrypted(false)
+        .set_legacy_values(LegacyValues::StringsOrEmpty)
+        .run()
+        .expect("Failed to migrate <0.3.0 store");
+    let kv: MicroKV = MicroKV::open_with_base_path("test_migrate_less_030_values", dir).unwrap();
+    let res: String = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    assert_eq!(res, "");
+}
+
+#[test]
+fn test_migrate_dry_run_and_backup() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_migrate_dry_run", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    // an unencrypted store written by 0.2.x, migrated through 0.3.0
+    let storage = Arc::new(RwLock::new(LegacyKV::new()));
+    let old = MicroKVLess030::create(
+        path.clone(),
+        None,
+        helpers::gen_nonce(),
+        false,
+        storage.clone(),
+    );
+    let value = old.encode_value(&"old value".to_string()).unwrap();
+    storage.write().unwrap().insert(KEY_NAME.to_string(), value);
+    old.commit().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+
+    let report = Migrate::new(path.clone()).set_dry_run(true).run().unwrap();
+    assert!(report.is_migrated());
+    assert_eq!(report.from, "<0.3.0");
+    assert_eq!(report.to, "0.4.0");
+    assert_eq!(
+        report.steps,
+        vec![
+            ("<0.3.0".to_string(), "0.3.0".to_string()),
+            ("0.3.0".to_string(), "0.4.0".to_string())
+        ]
+    );
+    assert_eq!((report.namespaces, report.entries), (1, 1));
+    assert_eq!(report.backup, None);
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+    let report = Migrate::new(path.clone()).run().unwrap();
+    let backup = report.backup.expect("no backup was written");
+    assert_eq!(std::fs::read(&backup).unwrap(), bytes);
+    assert!(format::read_header(&path).unwrap().is_some());
+
+    // a second migration, even within the same second, keeps the first backup
+    std::fs::write(&path, &bytes).unwrap();
+    let report = Migrate::new(path.clone()).run().unwrap();
+    let second = report.backup.expect("no backup was written");
+    assert_ne!(second, backup);
+    assert_eq!(std::fs::read(&backup).unwrap(), bytes);
+    assert_eq!(std::fs::read(&second).unwrap(), bytes);
+    std::fs::remove_file(&backup).unwrap();
+    std::fs::remove_file(&second).unwrap();
+
+    // a store in the current layout is left as it is
+    let report = Migrate::new(path.clone()).run().unwrap();
+    assert!(!report.is_migrated());
+    assert_eq!(report.backup, None);
+
+    let kv: MicroKV = MicroKV::open_with_base_path("test_migrate_dry_run", dir).unwrap();
+    let res: String = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    assert_eq!(res, "old value");
+}
+
+#[test]
+fn test_open_options() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_open_options", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let res = OpenOptions::new()
+        .must_exist(true)
+        .open_with_base_path("test_open_options", dir.clone());
+    assert!(matches!(res.err().unwrap().error, ErrorType::FileError));
+    let res = OpenOptions::new()
+        .read_only(true)
+        .open_with_base_path("test_open_options", dir.clone());
+    assert!(matches!(res.err().unwrap().error, ErrorType::FileError));
+
+    let kv: MicroKV = OpenOptions::new()
+        .create_new(true)
+        .with_pwd_clear(TEST_PASSWORD)
+        .open_with_base_path("test_open_options", dir.clone())
+        .unwrap();
+    assert!(path.is_file());
+    kv.put(KEY_NAME, &"value".to_string()).unwrap();
+    kv.commit().unwrap();
+    let res = OpenOptions::new()
+        .create_new(true)
+        .open_with_base_path("test_open_options", dir.clone());
+    assert!(matches!(res.err().unwrap().error, ErrorType::FileError));
+
+    // opening leaves the store file as it is
+    let bytes = std::fs::read(&path).unwrap();
+    let kv: MicroKV =
+        MicroKV::open_with_base_path_and_pwd("test_open_options", dir.clone(), TEST_PASSWORD)
+            .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+    let kv_read_only: MicroKV = OpenOptions::new()
+        .read_only(true)
+        .with_pwd_clear(TEST_PASSWORD)
+        .open_with_base_path("test_open_options", dir.clone())
+        .unwrap();
+    assert!(kv_read_only.is_read_only());
+    let res: String = kv_read_only.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "value");
+    let res = kv_read_only.put(KEY_NAME, &"other".to_string());
+    assert!(matches!(res.err().unwrap().error, ErrorType::ReadOnly));
+    let res = kv_read_only.delete(KEY_NAME);
+    assert!(matches!(res.err().unwrap().error, ErrorType::ReadOnly));
+    let res = kv_read_only.commit();
+    assert!(matches!(res.err().unwrap().error, ErrorType::ReadOnly));
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+    // a read-only handle still sees changes made by others
+    kv.put(KEY_NAME, &"changed".to_string()).unwrap();
+    kv.commit().unwrap();
+    let res: String = kv_read_only.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "changed");
+
+    // a store of 0.3.0 is only migrated in memory, or not at all
+    let _ = std::fs::remove_file(&path);
+    let pwd = SecVec::new(Sha256::digest(TEST_PASSWORD.as_bytes()).to_vec());
+    let storage = Arc::new(RwLock::new(HashMap::new()));
+    let old = MicroKV030::create(
+        path.clone(),
+        Some(pwd),
+        helpers::gen_nonce(),
+        false,
+        storage.clone(),
+    );
+    let mut data = LegacyKV::new();
+    data.insert(
+        KEY_NAME.to_string(),
+        old.encode_value(&"old value".to_string()).unwrap(),
+    );
+    storage
+        .write()
+        .unwrap()
+        .insert("".to_string(), Arc::new(RwLock::new(data)));
+    old.commit().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+
+    let res = OpenOptions::new()
+        .migrate(MigrateMode::Never)
+        .open_with_base_path("test_open_options", dir.clone());
+    assert!(matches!(
+        res.err().unwrap().error,
+        ErrorType::MigrateError(_, _)
+    ));
+    let kv: MicroKV = OpenOptions::new()
+        .read_only(true)
+        .with_pwd_clear(TEST_PASSWORD)
+        .open_with_base_path("test_open_options", dir.clone())
+        .unwrap();
+    let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "old value");
+    assert!(!kv.cipher().unwrap().is_legacy());
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+    // unlocking seals the values again in memory, and the handle writes them when it commits
+    let kv: MicroKV = OpenOptions::new()
+        .migrate(MigrateMode::Explicit)
+        .open_with_base_path("test_open_options", dir)
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    assert!(!kv.cipher().unwrap().is_legacy());
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    kv.commit().unwrap();
+    let header = format::read_header(&path).unwrap().unwrap();
+    assert!(!header.cipher.is_legacy());
+}
+
+#[test]
+fn test_open_read_only_dir() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    dir.push("test_open_read_only_dir");
+    let path = helpers::get_db_path_with_base_path("test_open_read_only_dir", dir.clone());
+    let mut lock_path = path.clone().into_os_string();
+    lock_path.push(".lock");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_open_read_only_dir", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put(KEY_NAME, &"value".to_string()).unwrap();
+    kv.commit().unwrap();
+    drop(kv);
+
+    // opening read-only creates nothing, not even the lock file
+    std::fs::remove_file(&lock_path).unwrap();
+    let mut permissions = std::fs::metadata(&dir).unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&dir, permissions.clone()).unwrap();
+    let res = OpenOptions::new()
+        .read_only(true)
+        .with_pwd_clear(TEST_PASSWORD)
+        .open_with_base_path("test_open_read_only_dir", dir.clone())
+        .and_then(|kv| kv.get_as_unwrap::<String>(KEY_NAME));
+    let entries = std::fs::read_dir(&dir).unwrap().count();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(&dir, permissions).unwrap();
+
+    assert_eq!(res.unwrap(), "value");
+    assert_eq!(entries, 1);
+    assert!(!std::path::Path::new(&lock_path).exists());
+}
+
+#[test]
+fn test_upgrade_legacy_kdf() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_upgrade_legacy_kdf", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_upgrade_legacy_kdf", dir.clone())
+        .with_kdf(Kdf::Sha256)
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put(KEY_NAME, &"legacy".to_string()).unwrap();
+    kv.commit().unwrap();
+
+    let kv: MicroKV = MicroKV::open_with_base_path("test_upgrade_legacy_kdf", dir.clone())
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    assert!(kv.kdf().unwrap().is_legacy());
+    kv.upgrade_kdf(TEST_PASSWORD, Kdf::argon2id()).unwrap();
+
+    let kv: MicroKV = MicroKV::open_with_base_path("test_upgrade_legacy_kdf", dir)
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    assert!(!kv.kdf().unwrap().is_legacy());
+    let res: String = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    assert_eq!(res, "legacy");
+}
+
+#[test]
+fn test_wrong_password() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_wrong_password", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_wrong_password", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put(KEY_NAME, &"secret".to_string()).unwrap();
+    kv.commit().unwrap();
+
+    // unlocking with a wrong password fails straight away
+    let err = MicroKV::open_with_base_path("test_wrong_password", dir.clone())
+        .unwrap()
+        .try_with_pwd_clear("wrong password")
+        .err()
+        .expect("wrong password unlocked the store");
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+
+    // and refuses reads and writes if not checked
+    let kv: MicroKV = MicroKV::open_with_base_path("test_wrong_password", dir.clone())
+        .unwrap()
+        .with_pwd_clear("wrong password");
+    assert!(kv.is_locked());
+    let err = kv.put("other", &"value".to_string()).unwrap_err();
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+    let err = kv.get(KEY_NAME).unwrap_err();
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+
+    let kv: MicroKV = MicroKV::open_with_base_path("test_wrong_password", dir)
+        .unwrap()
+        .try_with_pwd_clear(TEST_PASSWORD)
+        .expect("right password did not unlock the store");
+    let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "secret");
+    assert!(!kv.exists("other").unwrap());
+}
+
+#[test]
+fn test_change_password() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_change_password", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_change_password", dir.clone())
+        .set_auto_commit(true)
+        .with_pwd_clear(TEST_PASSWORD);
+    let namespace_one = kv.namespace("one");
+    kv.put("foo", &"bar".to_string()).unwrap();
+    namespace_one.put("zoo", &"big".to_string()).unwrap();
+
+    // the old password must be right
+    let err = kv
+        .change_password("wrong password", "new password")
+        .unwrap_err();
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+    kv.change_password(TEST_PASSWORD, "new password").unwrap();
+
+    // handles cloned before the change keep working with the new key
+    namespace_one.put("egg", &"gge".to_string()).unwrap();
+
+    let err = MicroKV::open_with_base_path("test_change_password", dir.clone())
+        .unwrap()
+        .try_with_pwd_clear(TEST_PASSWORD)
+        .err()
+        .expect("old password still unlocks the store");
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+
+    let kv: MicroKV = MicroKV::open_with_base_path("test_change_password", dir)
+        .unwrap()
+        .try_with_pwd_clear("new password")
+        .unwrap();
+    let namespace_one = kv.namespace("one");
+    assert_eq!(Some("bar".to_string()), kv.get_as("foo").unwrap());
+    assert_eq!(
+        Some("big".to_string()),
+        namespace_one.get_as("zoo").unwrap()
+    );
+    assert_eq!(
+        Some("gge".to_string()),
+        namespace_one.get_as("egg").unwrap()
+    );
+}
+
+#[test]
+fn test_key_slots() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_key_slots", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let keyfile = dir.join("test_key_slots.key");
+    std::fs::write(&keyfile, b"keyfile contents").unwrap();
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_key_slots", dir.clone())
+        .set_auto_commit(true)
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put(KEY_NAME, &"shared".to_string()).unwrap();
+    kv.add_key_slot("alice", Credential::password("alice password"))
+        .unwrap();
+    kv.add_key_slot("ci", Credential::keyfile(&keyfile).unwrap())
+        .unwrap();
+    let err = kv
+        .add_key_slot("alice", Credential::password("other password"))
+        .unwrap_err();
+    assert!(matches!(err.error, ErrorType::KVError));
+    let names: Vec<String> = kv
+        .key_slots()
+        .unwrap()
+        .into_iter()
+        .map(|s| s.name)
+        .collect();
+    assert_eq!(names, vec!["default", "alice", "ci"]);
+
+    // every slot unlocks the store
+    let kv: MicroKV = MicroKV::open_with_base_path("test_key_slots", dir.clone())
+        .unwrap()
+        .try_with_keyfile(&keyfile)
+        .unwrap();
+    let res: String = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    assert_eq!(res, "shared");
+    let kv: MicroKV = MicroKV::open_with_base_path("test_key_slots", dir.clone())
+        .unwrap()
+        .try_with_pwd_clear("alice password")
+        .unwrap();
+
+    // changing one password leaves the other slots alone, and removing a slot revokes it
+    kv.change_password("alice password", "new alice password")
+        .unwrap();
+    kv.remove_key_slot("ci").unwrap();
+    let err = MicroKV::open_with_base_path("test_key_slots", dir.clone())
+        .unwrap()
+        .try_with_keyfile(&keyfile)
+        .err()
+        .expect("removed slot still unlocks the store");
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+    for pwd in &[TEST_PASSWORD, "new alice password"] {
+        let kv: MicroKV = MicroKV::open_with_base_path("test_key_slots", dir.clone())
+            .unwrap()
+            .try_with_pwd_clear(pwd)
+            .unwrap();
+        let res: String = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+        assert_eq!(res, "shared");
+    }
+}
+
+#[test]
+fn test_key_provider() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_key_provider", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    env::set_var("MICROKV_TEST_PASSWORD", TEST_PASSWORD);
+    let kv: MicroKV = MicroKV::new_with_base_path("test_key_provider", dir.clone())
+        .try_with_key_provider(&EnvProvider::new("MICROKV_TEST_PASSWORD"))
+        .unwrap();
+    kv.put(KEY_NAME, &"provided".to_string()).unwrap();
+    kv.commit().unwrap();
+
+    // any closure is a provider
+    let kv: MicroKV = MicroKV::open_with_base_path("test_key_provider", dir.clone())
+        .unwrap()
+        .try_with_key_provider(&|| Ok(Credential::password(TEST_PASSWORD)))
+        .unwrap();
+    let res: String = kv.get_as_unwrap(KEY_NAME).expect("cannot retrieve value");
+    assert_eq!(res, "provided");
+
+    // a provider that can't read its key leaves the store locked
+    let err = MicroKV::open_with_base_path("test_key_provider", dir.clone())
+        .unwrap()
+        .try_with_key_provider(&EnvProvider::new("MICROKV_TEST_MISSING"))
+        .err()
+        .expect("missing variable unlocked the store");
+    assert!(matches!(err.error, ErrorType::ProviderError));
+    let kv: MicroKV = MicroKV::open_with_base_path("test_key_provider", dir)
+        .unwrap()
+        .with_key_provider(&EnvProvider::new("MICROKV_TEST_MISSING"));
+    assert!(kv.is_locked());
+}
+
+#[test]
+fn test_relocated_value() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_relocated_value", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV =
+        MicroKV::new_with_base_path("test_relocated_value", dir).with_pwd_clear(TEST_PASSWORD);
+    kv.put("admin_token", &"admin".to_string()).unwrap();
+    kv.put("guest_token", &"guest".to_string()).unwrap();
+
+    // move the ciphertext of one entry onto another, and into another namespace
+    let sealed = kv
+        .lock_read("", |data| data.get("admin_token").unwrap().clone())
+        .unwrap();
+    kv.lock_write("", |data| {
+        data.insert("guest_token".to_string(), sealed.clone())
+    })
+    .unwrap();
+    kv.lock_write("other", |data| {
+        data.insert("admin_token".to_string(), sealed.clone())
+    })
+    .unwrap();
+
+    let err = kv.get("guest_token").unwrap_err();
+    assert!(matches!(err.error, ErrorType::TamperError));
+    let err = kv.namespace("other").get("admin_token").unwrap_err();
+    assert!(matches!(err.error, ErrorType::TamperError));
+    let res: String = kv.get_as_unwrap("admin_token").unwrap();
+    assert_eq!(res, "admin");
+}
+
+#[test]
+fn test_integrity() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_integrity", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_integrity", dir.clone())
+        .set_auto_commit(true)
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put("guest_token", &"guest".to_string()).unwrap();
+    let old_copy = std::fs::read(&path).unwrap();
+    kv.put("admin_token", &"admin".to_string()).unwrap();
+
+    // an entry renamed in the file
+    let mut bytes = std::fs::read(&path).unwrap();
+    let at = bytes
+        .windows(b"guest_token".len())
+        .position(|w| w == b"guest_token")
+        .unwrap();
+    bytes[at] = b'G';
+    std::fs::write(&path, &bytes).unwrap();
+    let err = MicroKV::open_with_base_path("test_integrity", dir.clone())
+        .unwrap()
+        .try_with_pwd_clear(TEST_PASSWORD)
+        .err()
+        .expect("modified store was unlocked");
+    assert!(matches!(err.error, ErrorType::IntegrityError));
+
+    // an older copy of the file, seen by an open handle and by a new one
+    std::fs::write(&path, &old_copy).unwrap();
+    let err = kv.get("guest_token").unwrap_err();
+    assert!(matches!(err.error, ErrorType::IntegrityError));
+    let err = MicroKV::open_with_base_path("test_integrity", dir)
+        .unwrap()
+        .try_with_pwd_clear(TEST_PASSWORD)
+        .err()
+        .expect("rolled back store was unlocked");
+    assert!(matches!(err.error, ErrorType::IntegrityError));
+}
+
+#[test]
+fn test_blind_names() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_blind_names", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_blind_names", dir.clone())
+        .with_blind_names()
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put("stripe_live_key", &"sk_live".to_string()).unwrap();
+    kv.put("aws_secret", &"aws".to_string()).unwrap();
+    kv.namespace("payments")
+        .put("paypal_token", &"paypal".to_string())
+        .unwrap();
+    kv.commit().unwrap();
+
+    // names are not written to the file in clear
+    let bytes = std::fs::read(&path).unwrap();
+    for name in &["stripe_live_key", "aws_secret", "payments", "paypal_token"] {
+        assert!(!bytes.windows(name.len()).any(|w| w == name.as_bytes()));
+    }
+
+    // but can be listed and looked up once unlocked, also after re-keying
+    let kv: MicroKV = MicroKV::open_with_base_path("test_blind_names", dir)
+        .unwrap()
+        .try_with_pwd_clear(TEST_PASSWORD)
+        .unwrap();
+    kv.rekey_with_pwd_hash(Sha256::digest(TEST_PASSWORD.as_bytes()).into())
+        .unwrap();
+    assert_eq!(kv.keys().unwrap(), vec!["stripe_live_key", "aws_secret"]);
+    assert_eq!(
+        kv.sorted_keys().unwrap(),
+        vec!["aws_secret", "stripe_live_key"]
+    );
+    let mut namespaces = kv.namespaces().unwrap();
+    namespaces.sort();
+    assert_eq!(namespaces, vec!["", "payments"]);
+    let payments = kv.namespace("payments");
+    assert!(payments.exists("paypal_token").unwrap());
+    let res: String = payments.get_as_unwrap("paypal_token").unwrap();
+    assert_eq!(res, "paypal");
+    payments.clear().unwrap();
+    kv.commit().unwrap();
+    assert!(payments.keys().unwrap().is_empty());
+}
+
+#[test]
+fn test_namespace_keys() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_namespace_keys", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    // values already in a namespace are sealed again under its new key
+    let kv: MicroKV = MicroKV::new_with_base_path("test_namespace_keys", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD)
+        .set_auto_commit(true);
+    kv.put(KEY_NAME, &"store".to_string()).unwrap();
+    kv.namespace("payments")
+        .put("stripe", &"sk_live".to_string())
+        .unwrap();
+    let payments = kv.namespace("payments").with_pwd_clear("payments");
+    assert!(!payments.is_locked());
+    let res: String = payments.get_as_unwrap("stripe").unwrap();
+    assert_eq!(res, "sk_live");
+    kv.namespace("billing")
+        .with_pwd_clear("billing")
+        .put("invoice", &42)
+        .unwrap();
+
+    // the store password alone opens neither namespace
+    let kv: MicroKV = MicroKV::open_with_base_path("test_namespace_keys", dir)
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "store");
+    let err = kv.namespace("payments").get("stripe").unwrap_err();
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+    let mut namespaces = kv.namespace_infos().unwrap();
+    namespaces.sort_by(|a, b| a.name.cmp(&b.name));
+    let locked: Vec<(&str, bool)> = namespaces
+        .iter()
+        .map(|namespace| (namespace.name.as_str(), namespace.is_locked))
+        .collect();
+    assert_eq!(
+        locked,
+        vec![("", false), ("billing", true), ("payments", true)]
+    );
+
+    // nor does the key of another namespace
+    let err = kv
+        .namespace("payments")
+        .try_with_pwd_clear("billing")
+        .err()
+        .expect("payments was unlocked with the billing key");
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+    let billing = kv
+        .namespace("billing")
+        .try_with_pwd_clear("billing")
+        .unwrap();
+    let res: u64 = billing.get_as_unwrap("invoice").unwrap();
+    assert_eq!(res, 42);
+    assert!(kv.namespace("payments").is_locked());
+}
+
+#[test]
+fn test_cipher_suites() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    for (name, cipher, nonce_len) in &[
+        ("test_cipher_xchacha", Cipher::XChaCha20Poly1305, 24),
+        ("test_cipher_aes", Cipher::Aes256Gcm, 12),
+    ] {
+        let path = helpers::get_db_path_with_base_path(name, dir.clone());
+        let _ = std::fs::remove_file(&path);
+
+        let kv: MicroKV = MicroKV::new_with_base_path(name, dir.clone())
+            .with_cipher(*cipher)
+            .with_pwd_clear(TEST_PASSWORD);
+        kv.put(KEY_NAME, &"secret".to_string()).unwrap();
+        kv.commit().unwrap();
+
+        // the cipher is read back from the header, and can't be changed on an encrypted store
+        let kv: MicroKV = MicroKV::open_with_base_path(name, dir.clone())
+            .unwrap()
+            .with_cipher(Cipher::XChaCha20Poly1305)
+            .with_cipher(Cipher::Aes256Gcm)
+            .try_with_pwd_clear(TEST_PASSWORD)
+            .unwrap();
+        assert_eq!(kv.cipher().unwrap(), *cipher);
+        let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+        assert_eq!(res, "secret");
+        let nonce = kv
+            .lock_read("", |data| data[KEY_NAME].nonce.clone())
+            .unwrap();
+        assert_eq!(nonce.unwrap().len(), *nonce_len);
+    }
+}
+
+#[test]
+fn test_backend_vectors() {
+    // every crypto backend must derive, blind and open exactly the same bytes
+    let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+    let bytes = |len: u8| -> Vec<u8> { (0..len).collect() };
+
+    let kdf = Kdf::Argon2id {
+        salt: Salt::from_slice(&bytes(16)).unwrap(),
+        opslimit: 2,
+        memlimit: 8192,
+    };
+    let key = kdf.derive_key(TEST_PASSWORD.as_bytes()).unwrap();
+    assert_eq!(
+        hex(key.unsecure()),
+        "049eaaf9bfbe26b059e5d2376034696ecb4872e6854b2f4c4ba9a2603b63bf4f"
+    );
+
+    let key = SecVec::new(bytes(32));
+    assert_eq!(
+        helpers::blind_name(&key, b"key", b"name"),
+        "0c101179a959b2254fb61e3700b0ca46ef20faafd678f97a8c32ebcf47856eea"
+    );
+
+    // the legacy cipher ignores the associated data
+    let vectors = [
+        (
+            Cipher::XChaCha20Poly1305,
+            24,
+            "98c20f7f90d28dae402145bcae26c02387b8800f48f930171e7c5bdcdbea",
+        ),
+        (
+            Cipher::XSalsa20Poly1305,
+            24,
+            "0afbf67783d784a7d1492c79d4ca72b458ff384fc7caa210c859ec4c0dfb",
+        ),
+        (
+            Cipher::Aes256Gcm,
+            12,
+            "4102d61bc5e5c21bfe24f4f9d49d7371b98081d003d8e63db4405d41918b",
+        ),
+    ];
+    for (cipher, nonce_len, ciphertext) in vectors {
+        let ciphertext = (0..ciphertext.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&ciphertext[i..i + 2], 16).unwrap())
+            .collect();
+        let value = SealedValue::new(Some(bytes(nonce_len)), SecVec::new(ciphertext));
+        let res: String = helpers::open_value(&value, &Some(key.clone()), cipher, b"ad").unwrap();
+        assert_eq!(res, "secret", "{:?}", cipher);
+    }
+}
+
+/// Stands in for a KMS, wrapping keys with a fixed pad.
+struct TestWrapper {
+    id: &'static str,
+    pad: u8,
+}
+
+impl KeyWrapper for TestWrapper {
+    fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    fn wrap(&self, data_key: &[u8]) -> microkv::errors::Result<Vec<u8>> {
+        Ok(data_key.iter().map(|b| b ^ self.pad).collect())
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> microkv::errors::Result<SecStr> {
+        Ok(SecVec::new(wrapped.iter().map(|b| b ^ self.pad).collect()))
+    }
+}
+
+#[test]
+fn test_envelope_encryption() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_envelope_encryption", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let sealed = |kv: &MicroKV| {
+        kv.lock_read("", |data| data[KEY_NAME].data.unsecure().to_vec())
+            .unwrap()
+    };
+
+    // changing the password wraps the data key again, without sealing values again
+    let kv: MicroKV = MicroKV::new_with_base_path("test_envelope_encryption", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put(KEY_NAME, &"value".to_string()).unwrap();
+    kv.commit().unwrap();
+    let before = sealed(&kv);
+    kv.change_password(TEST_PASSWORD, "new password").unwrap();
+    assert_eq!(sealed(&kv), before);
+
+    // the data key can also be wrapped by an external service
+    let kms = TestWrapper {
+        id: "kms-a",
+        pad: 0x5a,
+    };
+    kv.add_key_slot("kms", Credential::wrapper(kms)).unwrap();
+    assert!(matches!(
+        kv.key_slots().unwrap()[1].kind,
+        SlotKind::Wrapper { ref id } if id == "kms-a"
+    ));
+    let kms = TestWrapper {
+        id: "kms-a",
+        pad: 0x5a,
+    };
+    let kv: MicroKV = MicroKV::open_with_base_path("test_envelope_encryption", dir.clone())
+        .unwrap()
+        .try_with_credential(Credential::wrapper(kms))
+        .unwrap();
+    let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "value");
+    assert_eq!(sealed(&kv), before);
+
+    let other = TestWrapper {
+        id: "kms-b",
+        pad: 0x5a,
+    };
+    let err = MicroKV::open_with_base_path("test_envelope_encryption", dir)
+        .unwrap()
+        .try_with_credential(Credential::wrapper(other))
+        .err()
+        .expect("store was unlocked by another wrapping key");
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+}
+
+#[test]
+fn test_drop_box() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_drop_box", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let (public_key, secret_key) = dropbox::gen_keypair();
+
+    // writers only know the public key, and can't read back what they put
+    let kv: MicroKV = MicroKV::new_with_base_path("test_drop_box", dir.clone())
+        .with_public_key(public_key)
+        .set_auto_commit(true);
+    kv.put("deploy_token", &"token".to_string()).unwrap();
+    let kv: MicroKV = MicroKV::open_with_base_path("test_drop_box", dir.clone())
+        .unwrap()
+        .set_auto_commit(true);
+    assert_eq!(kv.public_key().unwrap(), Some(public_key));
+    kv.namespace("ci")
+        .put("api_key", &"key".to_string())
+        .unwrap();
+    assert_eq!(kv.keys().unwrap(), vec!["deploy_token".to_string()]);
+    let err = kv.get("deploy_token").unwrap_err();
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+
+    // only the matching secret key reads them
+    let (_, other) = dropbox::gen_keypair();
+    let err = MicroKV::open_with_base_path("test_drop_box", dir.clone())
+        .unwrap()
+        .try_with_secret_key(other)
+        .err()
+        .expect("drop box was opened with another secret key");
+    assert!(matches!(err.error, ErrorType::InvalidPassword));
+    let kv: MicroKV = MicroKV::open_with_base_path("test_drop_box", dir)
+        .unwrap()
+        .try_with_secret_key(secret_key)
+        .unwrap();
+    let res: String = kv.get_as_unwrap("deploy_token").unwrap();
+    assert_eq!(res, "token");
+    let res: String = kv.namespace("ci").get_as_unwrap("api_key").unwrap();
+    assert_eq!(res, "key");
+
+    // sealed boxes are bound to their entry as well
+    let sealed = kv
+        .lock_read("", |data| data.get("deploy_token").unwrap().clone())
+        .unwrap();
+    kv.lock_write("ci", |data| {
+        data.insert("api_key".to_string(), sealed.clone())
+    })
+    .unwrap();
+    kv.commit().unwrap();
+    let err = kv.namespace("ci").get("api_key").unwrap_err();
+    assert!(matches!(err.error, ErrorType::TamperError));
+}
+
+#[test]
+fn test_padding_and_compression() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_padding_and_compression", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let sealed = |kv: &MicroKV, key: &str| {
+        kv.lock_read("", |data| data.get(key).unwrap().clone())
+            .unwrap()
+    };
+
+    // values written before padding is enabled keep their length
+    let kv: MicroKV = MicroKV::new_with_base_path("test_padding_and_compression", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD)
+        .set_auto_commit(true);
+    kv.put("plain", &"value".to_string()).unwrap();
+    assert_eq!(sealed(&kv, "plain").encoding, Encoding::default());
+
+    // an API key and a longer value of the same bucket can't be told apart
+    let kv = kv.with_padding(Padding::PowerOfTwo);
+    kv.put("api_key", &"0123456789abcdef".to_string()).unwrap();
+    kv.put("password", &"correct horse battery staple".to_string())
+        .unwrap();
+    let api_key = sealed(&kv, "api_key");
+    assert!(api_key.encoding.is_padded);
+    assert_eq!(
+        api_key.data.unsecure().len(),
+        sealed(&kv, "password").data.unsecure().len()
+    );
+
+    // large values shrink, and mixed encodings all decode
+    let kv = kv.with_compression(Compression::Deflate);
+    let blob: Vec<TestStruct> = (0..100)
+        .map(|id| TestStruct {
+            id,
+            name: "microkv".to_string(),
+        })
+        .collect();
+    kv.put("blob", &blob).unwrap();
+    let compressed = sealed(&kv, "blob");
+    assert_eq!(compressed.encoding.compression, Compression::Deflate);
+    assert!(compressed.data.unsecure().len() < serde_json::to_vec(&blob).unwrap().len() / 2);
+
+    let kv: MicroKV = MicroKV::open_with_base_path("test_padding_and_compression", dir)
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.rekey_with_pwd_hash([7; 32]).unwrap();
+    let res: String = kv.get_as_unwrap("plain").unwrap();
+    assert_eq!(res, "value");
+    let res: String = kv.get_as_unwrap("api_key").unwrap();
+    assert_eq!(res, "0123456789abcdef");
+    let res: Vec<TestStruct> = kv.get_as_unwrap("blob").unwrap();
+    assert_eq!(res.len(), 100);
+
+    // the encoding is bound to the value
+    kv.lock_write("", |data| {
+        data.get_mut("api_key").unwrap().encoding.is_padded = false
+    })
+    .unwrap();
+    kv.commit().unwrap();
+    let err = kv.get("api_key").unwrap_err();
+    assert!(matches!(err.error, ErrorType::TamperError));
+}
+
+#[test]
+fn test_atomic_commit() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_atomic_commit", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_atomic_commit", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD)
+        .set_auto_commit(true);
+    kv.put("certificate", &"-".repeat(4096)).unwrap();
+    kv.put(KEY_NAME, &"value".to_string()).unwrap();
+    let before = std::fs::metadata(&path).unwrap().len();
+
+    // a shorter store replaces the longer one entirely, without stale trailing bytes
+    kv.delete("certificate").unwrap();
+    let after = std::fs::metadata(&path).unwrap().len();
+    assert!(after < before - 4096);
+    let kv: MicroKV = MicroKV::open_with_base_path("test_atomic_commit", dir.clone())
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "value");
+
+    // no temporary file is left behind
+    let leftovers: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.contains("test_atomic_commit") && name.ends_with(".tmp"))
+        .collect();
+    assert!(leftovers.is_empty());
+}
+
+#[test]
+fn test_file_lock() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_file_lock", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let kv: MicroKV = MicroKV::new_with_base_path("test_file_lock", dir)
+        .with_pwd_clear(TEST_PASSWORD)
+        .with_lock_mode(LockMode::Try);
+    kv.put(KEY_NAME, &"value".to_string()).unwrap();
+    kv.commit().unwrap();
+
+    // another process holds the store file exclusively
+    let other = std::fs::File::open(path.with_extension("kv.lock")).unwrap();
+    FileExt::lock_exclusive(&other).unwrap();
+    let err = kv.commit().unwrap_err();
+    assert!(matches!(err.error, ErrorType::LockError));
+    // reads only wait for the lock once the store has changed
+    assert!(kv.get(KEY_NAME).is_ok());
+    std::fs::write(&path, std::fs::read(&path).unwrap()).unwrap();
+    let err = kv.get(KEY_NAME).unwrap_err();
+    assert!(matches!(err.error, ErrorType::LockError));
+
+    let kv = kv.with_lock_mode(LockMode::Timeout(Duration::from_millis(50)));
+    let start = Instant::now();
+    let err = kv.commit().unwrap_err();
+    assert!(matches!(err.error, ErrorType::LockError));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // blocking commits wait for the lock to be released
+    let kv = kv.with_lock_mode(LockMode::Blocking);
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        FileExt::unlock(&other).unwrap();
+    });
+    kv.commit().unwrap();
+    release.join().unwrap();
+    let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "value");
+}
+
+#[test]
+fn test_concurrent_handles() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    for pwd in [None, Some(TEST_PASSWORD)] {
+        let path = helpers::get_db_path_with_base_path("test_concurrent_handles", dir.clone());
+        let _ = std::fs::remove_file(&path);
+        let open = || -> MicroKV {
+            let kv = MicroKV::open_with_base_path("test_concurrent_handles", dir.clone())
+                .unwrap()
+                .set_auto_commit(true);
+            match pwd {
+                Some(pwd) => kv.with_pwd_clear(pwd),
+                None => kv,
+            }
+        };
+        open().put(KEY_NAME, &"value".to_string()).unwrap();
+
+        // two handles, as of two processes, commit at the same time without losing entries
+        let writers: Vec<_> = (0..2)
+            .map(|writer| {
+                let kv = open();
+                thread::spawn(move || {
+                    for ix in 0..50 {
+                        kv.put(format!("key-{}-{}", writer, ix), &ix).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let kv = open();
+        assert_eq!(kv.keys().unwrap().len(), 101);
+        for writer in 0..2 {
+            for ix in 0..50 {
+                let key = format!("key-{}-{}", writer, ix);
+                assert_eq!(kv.get_as_unwrap::<i32>(key).unwrap(), ix);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_write_ahead_log() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_write_ahead_log", dir.clone());
+    let log_path = path.with_extension("kv.wal");
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&log_path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_write_ahead_log", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD)
+        .set_auto_commit(true)
+        .set_write_ahead_log(true);
+    kv.put("first", &1).unwrap();
+    let checkpoint = std::fs::metadata(&path).unwrap().len();
+
+    // later puts and deletes only grow the log
+    kv.put("second", &2).unwrap();
+    kv.put("third", &3).unwrap();
+    kv.delete("first").unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), checkpoint);
+    let log_len = std::fs::metadata(&log_path).unwrap().len();
+
+    let open = || {
+        MicroKV::open_with_base_path("test_write_ahead_log", dir.clone())
+            .unwrap()
+            .with_pwd_clear(TEST_PASSWORD)
+            .set_auto_commit(true)
+            .set_write_ahead_log(true)
+    };
+    let kv: MicroKV = open();
+    assert!(!kv.exists("first").unwrap());
+    assert_eq!(kv.get_as_unwrap::<i32>("second").unwrap(), 2);
+    assert_eq!(kv.get_as_unwrap::<i32>("third").unwrap(), 3);
+
+    // compacting moves the records into the store file
+    kv.compact().unwrap();
+    assert!(std::fs::metadata(&log_path).unwrap().len() < log_len);
+    let kv: MicroKV = open();
+    assert_eq!(kv.get_as_unwrap::<i32>("second").unwrap(), 2);
+
+    // a record changed outside of microkv is detected
+    let checkpoint = std::fs::metadata(&path).unwrap().len();
+    kv.put("fourth", &4).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), checkpoint);
+    let mut log = std::fs::read(&log_path).unwrap();
+    let last = log.len() - 1;
+    log[last] ^= 1;
+    std::fs::write(&log_path, log).unwrap();
+    let err = MicroKV::open_with_base_path("test_write_ahead_log", dir)
+        .unwrap()
+        .try_with_pwd_clear(TEST_PASSWORD)
+        .err()
+        .unwrap();
+    assert!(matches!(err.error, ErrorType::IntegrityError));
+}
+
+#[test]
+fn test_change_detection() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_change_detection", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let kv: MicroKV = MicroKV::new_with_base_path("test_change_detection", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put("first", &1).unwrap();
+    kv.commit().unwrap();
+
+    // an unchanged store is not read again, so uncommitted entries are kept
+    kv.put("second", &2).unwrap();
+    assert_eq!(kv.get_as_unwrap::<i32>("second").unwrap(), 2);
+
+    // entries committed by another handle are read once the store changed
+    let other: MicroKV = MicroKV::open_with_base_path("test_change_detection", dir)
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD)
+        .set_auto_commit(true);
+    other.put("third", &3).unwrap();
+    assert_eq!(kv.get_as_unwrap::<i32>("third").unwrap(), 3);
+    assert_eq!(kv.get_as_unwrap::<i32>("second").unwrap(), 2);
+}
+
+#[test]
+fn test_merge_conflicts() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_merge_conflicts", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let kv: MicroKV = MicroKV::new_with_base_path("test_merge_conflicts", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD);
+    kv.put("shared", &0).unwrap();
+    kv.put("kept", &0).unwrap();
+    kv.commit().unwrap();
+
+    let conflicts: Arc<RwLock<Vec<Conflict>>> = Arc::new(RwLock::new(Vec::new()));
+    let reported = conflicts.clone();
+    let other: MicroKV = MicroKV::open_with_base_path("test_merge_conflicts", dir.clone())
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD)
+        .with_conflict_policy(ConflictPolicy::KeepStored)
+        .with_conflict_handler(move |conflict| reported.write().unwrap().push(conflict.clone()));
+
+    // both handles change the same entry, and the first commits
+    other.put("shared", &2).unwrap();
+    other.put("local", &2).unwrap();
+    kv.put("shared", &1).unwrap();
+    kv.put("kept", &1).unwrap();
+    kv.commit().unwrap();
+
+    // the stored change wins the conflict, and other entries are merged one by one
+    assert_eq!(other.get_as_unwrap::<i32>("shared").unwrap(), 1);
+    assert_eq!(other.get_as_unwrap::<i32>("kept").unwrap(), 1);
+    assert_eq!(other.get_as_unwrap::<i32>("local").unwrap(), 2);
+    let settled = conflicts.read().unwrap().clone();
+    assert_eq!(settled.len(), 1);
+    assert_eq!(settled[0].key, "shared");
+    assert_eq!(
+        (settled[0].local_version, settled[0].stored_version),
+        (1, 2)
+    );
+    assert!(!settled[0].kept_local);
+
+    // by default, the local change is kept until it is committed
+    kv.put("shared", &3).unwrap();
+    other.commit().unwrap();
+    assert_eq!(kv.get_as_unwrap::<i32>("shared").unwrap(), 3);
+    assert_eq!(kv.get_as_unwrap::<i32>("local").unwrap(), 2);
+    kv.commit().unwrap();
+    assert_eq!(other.get_as_unwrap::<i32>("shared").unwrap(), 3);
+
+    // a commit settles conflicts the same way, without reloading first
+    other.put("shared", &4).unwrap();
+    other.put("late", &4).unwrap();
+    kv.put("shared", &5).unwrap();
+    kv.commit().unwrap();
+    other.commit().unwrap();
+    let settled = conflicts.read().unwrap().clone();
+    assert_eq!(settled.len(), 2);
+    assert_eq!(settled[1].key, "shared");
+    assert!(!settled[1].kept_local);
+    let kv: MicroKV = MicroKV::open_with_base_path("test_merge_conflicts", dir.clone())
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    assert_eq!(kv.get_as_unwrap::<i32>("shared").unwrap(), 5);
+    assert_eq!(kv.get_as_unwrap::<i32>("late").unwrap(), 4);
+    assert_eq!(kv.get_as_unwrap::<i32>("local").unwrap(), 2);
+}
+
+#[test]
+fn test_container_format() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_container_format", dir.clone());
+    let _ = std::fs::remove_file(&path);
+    let kv: MicroKV = MicroKV::new_with_base_path("test_container_format", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD)
+        .with_blind_names();
+    kv.put(KEY_NAME, &"value".to_string()).unwrap();
+    kv.commit().unwrap();
+
+    // the header describes the store without opening it, and the path is not recorded
+    let bytes = std::fs::read(&path).unwrap();
+    assert!(bytes.starts_with(format::MAGIC));
+    let header = format::read_header(&path).unwrap().unwrap();
+    assert_eq!(header.store_version, "0.4.0");
+    assert_eq!(header.cipher, Cipher::default());
+    assert_eq!(
+        header.flags,
+        format::FLAG_ENCRYPTED | format::FLAG_BLIND_NAMES
+    );
+    let name = path.to_string_lossy().into_owned();
+    assert!(!bytes.windows(name.len()).any(|w| w == name.as_bytes()));
+
+    // a moved store opens from its new path
+    let moved = helpers::get_db_path_with_base_path("test_container_format_moved", dir.clone());
+    std::fs::rename(&path, &moved).unwrap();
+    let kv: MicroKV = MicroKV::open_with_base_path("test_container_format_moved", dir.clone())
+        .unwrap()
+        .with_pwd_clear(TEST_PASSWORD);
+    let res: String = kv.get_as_unwrap(KEY_NAME).unwrap();
+    assert_eq!(res, "value");
+
+    // a store of a newer format is not taken for an older one
+    let mut bytes = std::fs::read(&moved).unwrap();
+    bytes[8] = 0xff;
+    std::fs::write(&moved, bytes).unwrap();
+    assert!(MicroKV::open_with_base_path("test_container_format_moved", dir).is_err());
+}
+
+#[test]
+fn test_transaction() {
+    let mut dir = env::temp_dir();
+    dir.push("microkv");
+    let path = helpers::get_db_path_with_base_path("test_transaction", dir.clone());
+    let _ = std::fs::remove_file(&path);
+
+    let kv: MicroKV = MicroKV::new_with_base_path("test_transaction", dir.clone())
+        .with_pwd_clear(TEST_PASSWORD)
+        .with_blind_names()
+        .set_auto_commit(true);
+    kv.namespace("legacy").put("token", &"old").unwrap();
+
+    kv.transaction(|tx| {
+        tx.namespace("oauth").put("client_id", &"id")?;
+        tx.namespace("oauth").put("client_secret", &"secret")?;
+        tx.namespace("legacy").delete("token")?;
+
+        // writes are only seen by the transaction until it ends
+        let res: Option<String> = tx.namespace("oauth").get_as("client_id")?;
+        assert_eq!(res.as_deref(), Some("id"));
+        assert!(!tx.namespace("legacy").exists("token")?);
+        assert_eq!(kv.namespace("oauth").get("client_id")?, None);
+        assert!(kv.namespace("legacy").exists("token")?);
+        Ok(())
+    })
+    .unwrap();
+
+    // a failing transaction changes nothing
+    let res = kv.transaction(|tx| {
+        tx.namespace("oauth").put("client_secret", &"leaked")?;
+        Err::<(), _>(microkv::errors::KVError {
+            error: ErrorType::Custom,
+            msg: None,
+        })
+    });
+    assert!(res.is_err());
+
+    let kv: MicroKV =
+        MicroKV::open_with_base_path_and_pwd("test_transaction", dir, TEST_PASSWORD).unwrap();
+    let res: String = kv.namespace("oauth").get_as_unwrap("client_id").unwrap();
+    assert_eq!(res, "id");
+    let res: String = kv
+        .namespace("oauth")
+        .get_as_unwrap("client_secret")
+        .unwrap();
+    assert_eq!(res, "secret");
+    assert!(!kv.namespace("legacy").exists("token").unwrap());
+    let names = kv.namespaces().unwrap();
+    assert!(names.contains(&"oauth".to_string()));
+
+    // a transaction that can't be persisted is rolled back
+    let kv = kv.with_lock_mode(LockMode::Try).set_auto_commit(true);
+    let mut lock_path = path.into_os_string();
+    lock_path.push(".lock");
+    let other = std::fs::File::open(&lock_path).unwrap();
+    FileExt::lock_shared(&other).unwrap();
+    let res = kv.transaction(|tx| tx.namespace("oauth").put("client_secret", &"lost"));
+    assert!(matches!(res.err().unwrap().error, ErrorType::LockError));
+    FileExt::unlock(&other).unwrap();
+    let res: String = kv
+        .namespace("oauth")
+        .get_as_unwrap("client_secret")
+        .unwrap();
+    assert_eq!(res, "secret");
+}